@@ -0,0 +1,74 @@
+// Archive (*.zip, *.tar, *.tar.gz, *.tgz) member iteration
+//
+// Members are handed out with their path inside the archive, directories are skipped.
+
+use std::fs::File;
+use std::io::Read;
+
+use flate2::read::GzDecoder;
+
+use crate::ReadError;
+
+const ARCHIVE_EXTENSIONS: [&str; 4] = [".zip", ".tar", ".tar.gz", ".tgz"];
+
+pub fn is_archive(filename: &str) -> bool {
+    ARCHIVE_EXTENSIONS.iter().any(|extension| filename.ends_with(extension))
+}
+
+// 2021.tar.gz -> 2021
+pub fn strip_extension(filename: &str) -> &str {
+    ARCHIVE_EXTENSIONS
+        .iter()
+        .find_map(|extension| filename.strip_suffix(extension))
+        .unwrap_or(filename)
+}
+
+fn for_each_zip_member<F>(file: File, callback: &mut F) -> Result<(), ReadError>
+where
+    F: FnMut(&str, Vec<u8>),
+{
+    let mut archive = zip::ZipArchive::new(file)?;
+    for index in 0..archive.len() {
+        let mut member = archive.by_index(index)?;
+        if !member.is_file() {
+            continue;
+        }
+        let mut data = Vec::new();
+        member.read_to_end(&mut data)?;
+        callback(member.name(), data);
+    }
+    Ok(())
+}
+
+fn for_each_tar_member<R, F>(reader: R, callback: &mut F) -> Result<(), ReadError>
+where
+    R: Read,
+    F: FnMut(&str, Vec<u8>),
+{
+    let mut archive = tar::Archive::new(reader);
+    for member in archive.entries()? {
+        let mut member = member?;
+        if !member.header().entry_type().is_file() {
+            continue;
+        }
+        let name = member.path()?.display().to_string();
+        let mut data = Vec::new();
+        member.read_to_end(&mut data)?;
+        callback(&name, data);
+    }
+    Ok(())
+}
+
+pub fn for_each_member<F>(fullpath: &str, mut callback: F) -> Result<(), ReadError>
+where
+    F: FnMut(&str, Vec<u8>),
+{
+    let file = File::open(fullpath)?;
+    if fullpath.ends_with(".zip") {
+        for_each_zip_member(file, &mut callback)
+    } else if fullpath.ends_with(".tar") {
+        for_each_tar_member(file, &mut callback)
+    } else {
+        for_each_tar_member(GzDecoder::new(file), &mut callback)
+    }
+}
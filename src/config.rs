@@ -0,0 +1,528 @@
+// Optional TOML configuration file (--config)
+//
+// [types]
+// unmapped = "drop"          # drop | keep | default
+// default = "other"          # category for unmapped types with unmapped = "default"
+//
+// [types.aliases]            # category = [raw types], wildcards are allowed
+// walking = ["walking", "hiking"]
+// cycling = ["cycling", "*_biking"]
+//
+// [infer]                    # activity type from the moving speed
+// mode = "missing"           # off | missing | override
+// walking_max_kmh = 7.0      # faster than cycling_max_kmh is driving
+//
+// [groups]                   # custom group membership
+// min_fraction = 0.5         # share of the track inside the region
+// measure = "points"         # points | length
+// time_zone = "Europe/Berlin" # for the date, weekday and hour criteria, IANA name or fixed offset like "+01:00"
+// mode = "priority"          # priority | tags
+//
+// [novelty]                  # when a track adds anything to the tracks before it
+// method = "grid"            # grid | buffer
+// cell_m = 11.0              # grid: a point is new in a cell without earlier points
+// radius_m = 15.0            # buffer: a point is new without earlier points within this distance
+// min_new_cells = 1          # a track is dropped below any of these minimums
+// min_new_length_m = 0.0
+// min_new_percent = 0.0      # new length in percent of the track length
+// clip = false               # only keep the new parts of a track
+// clip_margin = 1            # known points kept around every new part, so the lines join
+//
+// [novelty.shared]           # categories checked against one common set of seen points
+// foot = ["walking", "running"]
+//
+// [simplify]                 # removal of points which hardly change the track
+// method = "rdp"             # off | rdp | visvalingam
+// tolerance_m = 1.0
+//
+// [simplify.types.cycling]   # per category, missing values are taken from [simplify]
+// tolerance_m = 5.0
+//
+// [ids]                      # activity id for the custom group id ranges
+// patterns = ['^(?P<id>\d+)\.']  # tried in order on the file name, the named group id is the id
+
+use std::collections::BTreeMap;
+use std::collections::HashMap;
+use std::fs;
+
+use chrono::DateTime;
+use chrono::FixedOffset;
+use chrono::NaiveDateTime;
+use chrono::Utc;
+use chrono_tz::Tz;
+use globset::GlobBuilder;
+use globset::GlobMatcher;
+use regex::Regex;
+use serde::Deserialize;
+
+#[derive(Deserialize, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub types: TypeConfig,
+    pub infer: InferConfig,
+    pub groups: GroupConfig,
+    pub ids: IdConfig,
+    pub novelty: NoveltyConfig,
+    pub simplify: SimplifyConfig,
+}
+
+#[derive(Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Unmapped {
+    Drop,
+    Keep,
+    Default,
+}
+
+#[derive(Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TypeConfig {
+    aliases: BTreeMap<String, Vec<String>>,
+    unmapped: Unmapped,
+    default: String,
+
+    // compiled from aliases by load_config
+    #[serde(skip)]
+    exact: HashMap<String, String>,
+    #[serde(skip)]
+    wildcards: Vec<(GlobMatcher, String)>,
+}
+
+impl Default for TypeConfig {
+    fn default() -> Self {
+        // the historic behaviour: hiking counts as walking, everything else is dropped
+        let mut aliases = BTreeMap::new();
+        aliases.insert("walking".to_string(), vec!["walking".to_string(), "hiking".to_string()]);
+        aliases.insert("running".to_string(), vec!["running".to_string()]);
+        aliases.insert("cycling".to_string(), vec!["cycling".to_string()]);
+
+        let mut type_config = TypeConfig {
+            aliases,
+            unmapped: Unmapped::Drop,
+            default: "other".to_string(),
+            exact: HashMap::new(),
+            wildcards: Vec::new(),
+        };
+        type_config.compile().unwrap();
+        type_config
+    }
+}
+
+impl TypeConfig {
+    fn compile(&mut self) -> Result<(), String> {
+        self.exact.clear();
+        self.wildcards.clear();
+        for (category, raw_types) in &self.aliases {
+            for raw_type in raw_types {
+                if raw_type.contains(['*', '?', '[', '{']) {
+                    let glob = GlobBuilder::new(raw_type)
+                        .case_insensitive(true)
+                        .build()
+                        .map_err(|e| format!("invalid type pattern {}: {}", raw_type, e))?;
+                    self.wildcards.push((glob.compile_matcher(), category.clone()));
+                } else {
+                    self.exact.insert(raw_type.to_lowercase(), category.clone());
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // Output category of a raw activity type, None if it should be dropped.
+    // Exact names win over wildcards, wildcards are tried in category order.
+    pub fn map_type(&self, raw_type: &str) -> Option<String> {
+        if let Some(category) = self.exact.get(&raw_type.to_lowercase()) {
+            return Some(category.clone());
+        }
+        for (matcher, category) in &self.wildcards {
+            if matcher.is_match(raw_type) {
+                return Some(category.clone());
+            }
+        }
+
+        match self.unmapped {
+            Unmapped::Drop => None,
+            Unmapped::Keep => Some(kept_category(raw_type)),
+            Unmapped::Default => Some(self.default.clone()),
+        }
+    }
+}
+
+// A kept raw type becomes an output directory name, "x/../../escaped" must stay inside the output directory
+fn kept_category(raw_type: &str) -> String {
+    raw_type
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() || c == '_' || c == '-' { c } else { '_' })
+        .collect()
+}
+
+#[derive(Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum InferMode {
+    // never, a track without type is an error
+    Off,
+    // only for tracks without type
+    Missing,
+    // also replace a declared type which does not match the speed
+    Override,
+}
+
+#[derive(Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct InferConfig {
+    pub mode: InferMode,
+    // percentile of the moving speeds which is compared to the thresholds
+    pub percentile: f64,
+    // slower is treated as standing still and ignored
+    pub min_moving_kmh: f64,
+    pub walking_max_kmh: f64,
+    pub running_max_kmh: f64,
+    pub cycling_max_kmh: f64,
+}
+
+impl Default for InferConfig {
+    fn default() -> Self {
+        InferConfig {
+            mode: InferMode::Missing,
+            percentile: 50.0,
+            min_moving_kmh: 1.0,
+            walking_max_kmh: 7.0,
+            running_max_kmh: 16.0,
+            cycling_max_kmh: 45.0,
+        }
+    }
+}
+
+impl InferConfig {
+    fn check(&self) -> Result<(), String> {
+        if !(0.0..=100.0).contains(&self.percentile) || self.min_moving_kmh < 0.0 {
+            return Err("infer.percentile has to be between 0 and 100, infer.min_moving_kmh positive".to_string());
+        }
+        // a threshold below the one before it makes a type unreachable
+        if self.walking_max_kmh > self.running_max_kmh || self.running_max_kmh > self.cycling_max_kmh {
+            return Err(format!(
+                "infer thresholds have to increase: walking_max_kmh {} <= running_max_kmh {} <= cycling_max_kmh {}",
+                self.walking_max_kmh, self.running_max_kmh, self.cycling_max_kmh
+            ));
+        }
+        Ok(())
+    }
+}
+
+#[derive(Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum GroupMeasure {
+    Points,
+    Length,
+}
+
+#[derive(Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum GroupMode {
+    // the matching group with the highest priority replaces the type
+    Priority,
+    // the activity is written once for every matching group
+    Tags,
+}
+
+#[derive(Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct GroupConfig {
+    pub min_fraction: f64,
+    pub measure: GroupMeasure,
+    pub time_zone: String,
+    pub mode: GroupMode,
+}
+
+impl Default for GroupConfig {
+    fn default() -> Self {
+        GroupConfig {
+            min_fraction: 0.5,
+            measure: GroupMeasure::Points,
+            time_zone: "UTC".to_string(),
+            mode: GroupMode::Priority,
+        }
+    }
+}
+
+impl GroupConfig {
+    pub fn time_zone(&self) -> GroupTimeZone {
+        // checked by load_config
+        GroupTimeZone::parse(&self.time_zone).unwrap()
+    }
+}
+
+// A fixed offset ignores summer time, an IANA name follows it
+#[derive(Clone, Copy)]
+pub enum GroupTimeZone {
+    Named(Tz),
+    Fixed(FixedOffset),
+}
+
+impl GroupTimeZone {
+    pub fn parse(text: &str) -> Result<GroupTimeZone, String> {
+        if let Ok(offset) = text.parse::<FixedOffset>() {
+            return Ok(GroupTimeZone::Fixed(offset));
+        }
+        text.parse::<Tz>()
+            .map(GroupTimeZone::Named)
+            .map_err(|_| "expected an IANA time zone name like \"Europe/Berlin\" or an offset like \"+01:00\"".to_string())
+    }
+
+    pub fn local_time(&self, time: DateTime<Utc>) -> NaiveDateTime {
+        match self {
+            GroupTimeZone::Named(tz) => time.with_timezone(tz).naive_local(),
+            GroupTimeZone::Fixed(offset) => time.with_timezone(offset).naive_local(),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct IdConfig {
+    patterns: Vec<String>,
+
+    // compiled from patterns by load_config
+    #[serde(skip)]
+    regexes: Vec<Regex>,
+}
+
+impl Default for IdConfig {
+    fn default() -> Self {
+        let mut id_config = IdConfig {
+            patterns: vec![
+                // activity_123.gpx, 2023-05-01_123_Tour.gpx: the second _ or . separated part
+                r"^[^_.]*[_.](?P<id>\d+)(?:[_.]|$)".to_string(),
+                // 123.gpx
+                r"^(?P<id>\d+)\.".to_string(),
+            ],
+            regexes: Vec::new(),
+        };
+        id_config.compile().unwrap();
+        id_config
+    }
+}
+
+impl IdConfig {
+    fn compile(&mut self) -> Result<(), String> {
+        self.regexes.clear();
+        for pattern in &self.patterns {
+            let regex = Regex::new(pattern).map_err(|e| format!("invalid id pattern {}: {}", pattern, e))?;
+            if !regex.capture_names().any(|name| name == Some("id")) {
+                return Err(format!("id pattern {} has no (?P<id>...) group", pattern));
+            }
+            self.regexes.push(regex);
+        }
+        Ok(())
+    }
+
+    // Id of the first pattern matching the file name
+    pub fn extract_id(&self, filename: &str) -> Option<i64> {
+        self.regexes
+            .iter()
+            .filter_map(|regex| regex.captures(filename))
+            .find_map(|captures| captures["id"].parse::<i64>().ok())
+    }
+}
+
+#[derive(Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum NoveltyMethod {
+    Grid,
+    Buffer,
+}
+
+#[derive(Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct NoveltyConfig {
+    pub method: NoveltyMethod,
+    pub cell_m: f64,
+    pub radius_m: f64,
+    pub min_new_cells: usize,
+    pub min_new_length_m: f64,
+    pub min_new_percent: f64,
+    pub clip: bool,
+    pub clip_margin: usize,
+    // name = [categories], every other category is checked on its own
+    pub shared: BTreeMap<String, Vec<String>>,
+}
+
+impl Default for NoveltyConfig {
+    fn default() -> Self {
+        // about the size of the historic 4 decimal degree grid at the equator
+        NoveltyConfig {
+            method: NoveltyMethod::Grid,
+            cell_m: 11.0,
+            radius_m: 15.0,
+            // one new point is enough
+            min_new_cells: 1,
+            min_new_length_m: 0.0,
+            min_new_percent: 0.0,
+            clip: false,
+            clip_margin: 1,
+            shared: BTreeMap::new(),
+        }
+    }
+}
+
+impl NoveltyConfig {
+    fn check_shared(&self) -> Result<(), String> {
+        let mut seen: HashMap<&str, &str> = HashMap::new();
+        for (name, categories) in &self.shared {
+            for category in categories {
+                if let Some(other) = seen.insert(category, name) {
+                    return Err(format!("category {} is in novelty.shared {} and {}", category, other, name));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // Name of the shared set of a category, None if it is checked on its own
+    pub fn shared_set(&self, category: &str) -> Option<&str> {
+        self.shared
+            .iter()
+            .find(|(_, categories)| categories.iter().any(|shared| shared == category))
+            .map(|(name, _)| name.as_str())
+    }
+}
+
+#[derive(Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum SimplifyMethod {
+    Off,
+    // Ramer-Douglas-Peucker
+    Rdp,
+    // Visvalingam-Whyatt
+    Visvalingam,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SimplifyRule {
+    method: Option<SimplifyMethod>,
+    tolerance_m: Option<f64>,
+}
+
+#[derive(Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SimplifyConfig {
+    method: SimplifyMethod,
+    tolerance_m: f64,
+    types: BTreeMap<String, SimplifyRule>,
+}
+
+impl Default for SimplifyConfig {
+    fn default() -> Self {
+        SimplifyConfig {
+            method: SimplifyMethod::Rdp,
+            tolerance_m: 1.0,
+            types: BTreeMap::new(),
+        }
+    }
+}
+
+impl SimplifyConfig {
+    fn check(&self) -> Result<(), String> {
+        let tolerances = self.types.values().filter_map(|rule| rule.tolerance_m);
+        if std::iter::once(self.tolerance_m).chain(tolerances).any(|tolerance_m| tolerance_m < 0.0) {
+            return Err("simplify tolerance_m can not be negative".to_string());
+        }
+        Ok(())
+    }
+
+    // Method and tolerance for a category
+    pub fn for_type(&self, category: &str) -> (SimplifyMethod, f64) {
+        match self.types.get(category) {
+            Some(rule) => (rule.method.unwrap_or(self.method), rule.tolerance_m.unwrap_or(self.tolerance_m)),
+            None => (self.method, self.tolerance_m),
+        }
+    }
+}
+
+pub fn load_config(path: &str) -> Result<Config, String> {
+    let text = fs::read_to_string(path).map_err(|e| e.to_string())?;
+    let mut config: Config = toml::from_str(&text).map_err(|e| e.to_string())?;
+    config.types.compile()?;
+    config.ids.compile()?;
+    config.infer.check()?;
+    if !(0.0..=1.0).contains(&config.groups.min_fraction) {
+        return Err(format!("groups.min_fraction {} is not between 0 and 1", config.groups.min_fraction));
+    }
+    if config.novelty.cell_m <= 0.0 || config.novelty.radius_m <= 0.0 {
+        return Err("novelty.cell_m and novelty.radius_m have to be positive".to_string());
+    }
+    if config.novelty.min_new_length_m < 0.0 || !(0.0..=100.0).contains(&config.novelty.min_new_percent) {
+        return Err("novelty.min_new_length_m has to be positive, novelty.min_new_percent between 0 and 100".to_string());
+    }
+    config.novelty.check_shared()?;
+    config.simplify.check()?;
+    GroupTimeZone::parse(&config.groups.time_zone)
+        .map_err(|e| format!("invalid groups.time_zone {}: {}", config.groups.time_zone, e))?;
+    Ok(config)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn local_hour(time_zone: &str, time: &str) -> u32 {
+        use chrono::Timelike;
+        let time = time.parse::<DateTime<Utc>>().unwrap();
+        GroupTimeZone::parse(time_zone).unwrap().local_time(time).hour()
+    }
+
+    #[test]
+    fn named_time_zone_follows_summer_time() {
+        assert_eq!(local_hour("Europe/Berlin", "2023-01-10T06:30:00Z"), 7);
+        assert_eq!(local_hour("Europe/Berlin", "2023-07-10T06:30:00Z"), 8);
+        assert_eq!(local_hour("+01:00", "2023-07-10T06:30:00Z"), 7);
+        assert_eq!(local_hour("UTC", "2023-07-10T06:30:00Z"), 6);
+    }
+
+    #[test]
+    fn kept_types_are_safe_directory_names() {
+        let types = TypeConfig { unmapped: Unmapped::Keep, ..TypeConfig::default() };
+        assert_eq!(types.map_type("Nordic-Walking_2").as_deref(), Some("Nordic-Walking_2"));
+        assert_eq!(types.map_type("x/../../../escaped").as_deref(), Some("x__________escaped"));
+        assert_eq!(types.map_type("e bike\\tour").as_deref(), Some("e_bike_tour"));
+    }
+
+    #[test]
+    fn infer_thresholds_are_checked() {
+        assert!(InferConfig::default().check().is_ok());
+        let swapped = InferConfig { walking_max_kmh: 20.0, running_max_kmh: 10.0, ..InferConfig::default() };
+        assert!(swapped.check().is_err());
+        let percentile = InferConfig { percentile: 500.0, ..InferConfig::default() };
+        assert!(percentile.check().is_err());
+        let min_moving = InferConfig { min_moving_kmh: -1.0, ..InferConfig::default() };
+        assert!(min_moving.check().is_err());
+    }
+
+    #[test]
+    fn default_id_patterns() {
+        let ids = IdConfig::default();
+        assert_eq!(ids.extract_id("activity_123.gpx"), Some(123));
+        assert_eq!(ids.extract_id("activity_123_x.gpx"), Some(123));
+        assert_eq!(ids.extract_id("123.gpx"), Some(123));
+        assert_eq!(ids.extract_id("123.gpx.gz"), Some(123));
+        assert_eq!(ids.extract_id("2023-05-01_123_Tour.gpx"), Some(123));
+        assert_eq!(ids.extract_id("a.b_123.gpx"), None);
+        assert_eq!(ids.extract_id("Morning_Run.gpx"), None);
+    }
+
+    #[test]
+    fn id_pattern_needs_id_group() {
+        let mut ids = IdConfig { patterns: vec![r"^ride-(\d+)".to_string()], regexes: Vec::new() };
+        assert!(ids.compile().is_err());
+        let mut ids = IdConfig { patterns: vec![r"^ride-(?P<id>\d+)".to_string()], regexes: Vec::new() };
+        assert!(ids.compile().is_ok());
+        assert_eq!(ids.extract_id("ride-42.fit"), Some(42));
+    }
+
+    #[test]
+    fn invalid_time_zone() {
+        assert!(GroupTimeZone::parse("Europe/Nowhere").is_err());
+        assert!(GroupTimeZone::parse("+25:00").is_err());
+    }
+}
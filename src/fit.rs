@@ -0,0 +1,458 @@
+// Garmin FIT (*.fit) reader
+//
+// Only the messages needed for a track are decoded:
+// - record (20): position, altitude, time and sensor values
+// - event (21): timer stop starts a new segment
+// - session (18) / sport (12): activity type
+// See the FIT SDK profile for the message and field numbers.
+
+use std::collections::HashMap;
+
+use chrono::DateTime;
+use chrono::Utc;
+
+use crate::CoordsFile;
+use crate::Options;
+use crate::ReadError;
+use crate::TrackPoint;
+
+const MESG_SPORT: u16 = 12;
+const MESG_SESSION: u16 = 18;
+const MESG_RECORD: u16 = 20;
+const MESG_EVENT: u16 = 21;
+
+// FIT timestamps count seconds since 1989-12-31T00:00:00Z
+const FIT_EPOCH_OFFSET: i64 = 631065600;
+
+// sport enum of the FIT profile, index is the enum value
+const SPORTS: [&str; 49] = [
+    "generic", "running", "cycling", "transition", "fitness_equipment", "swimming",
+    "basketball", "soccer", "tennis", "american_football", "training", "walking",
+    "cross_country_skiing", "alpine_skiing", "snowboarding", "rowing", "mountaineering",
+    "hiking", "multisport", "paddling", "flying", "e_biking", "motorcycling", "boating",
+    "driving", "golf", "hang_gliding", "horseback_riding", "hunting", "fishing",
+    "inline_skating", "rock_climbing", "sailing", "ice_skating", "sky_diving",
+    "snowshoeing", "snowmobiling", "stand_up_paddleboarding", "surfing", "wakeboarding",
+    "water_skiing", "kayaking", "rafting", "windsurfing", "kitesurfing", "tactical",
+    "jumpmaster", "boxing", "floor_climbing",
+];
+
+struct FieldDefinition {
+    number: u8,
+    size: usize,
+}
+
+struct MessageDefinition {
+    big_endian: bool,
+    global_number: u16,
+    fields: Vec<FieldDefinition>,
+    // developer fields are skipped, only their size matters
+    developer_size: usize,
+}
+
+struct Reader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn bytes(&mut self, size: usize) -> Result<&'a [u8], ReadError> {
+        if self.pos + size > self.data.len() {
+            return Err(ReadError::Fit("unexpected end of file".to_string()));
+        }
+        let bytes = &self.data[self.pos..self.pos + size];
+        self.pos += size;
+        Ok(bytes)
+    }
+
+    fn u8(&mut self) -> Result<u8, ReadError> {
+        Ok(self.bytes(1)?[0])
+    }
+}
+
+fn read_unsigned(bytes: &[u8], big_endian: bool) -> u64 {
+    let mut value: u64 = 0;
+    for i in 0..bytes.len() {
+        let byte = if big_endian { bytes[i] } else { bytes[bytes.len() - 1 - i] };
+        value = (value << 8) | byte as u64;
+    }
+    value
+}
+
+// Decoded values of one data message, invalid values are already removed
+type Fields = HashMap<u8, u64>;
+
+fn is_invalid(value: u64, size: usize, signed: bool) -> bool {
+    match (size, signed) {
+        (1, false) => value == 0xFF,
+        (1, true) => value == 0x7F,
+        (2, false) => value == 0xFFFF,
+        (2, true) => value == 0x7FFF,
+        (4, false) => value == 0xFFFF_FFFF,
+        (4, true) => value == 0x7FFF_FFFF,
+        _ => false,
+    }
+}
+
+fn signed_fields(global_number: u16) -> &'static [u8] {
+    match global_number {
+        // position_lat, position_long, temperature
+        MESG_RECORD => &[0, 1, 13],
+        _ => &[],
+    }
+}
+
+fn read_fields(reader: &mut Reader, definition: &MessageDefinition) -> Result<Fields, ReadError> {
+    let mut fields = Fields::new();
+    let signed = signed_fields(definition.global_number);
+    for field in &definition.fields {
+        let bytes = reader.bytes(field.size)?;
+        // arrays and strings are not needed
+        if field.size > 8 {
+            continue;
+        }
+        let value = read_unsigned(bytes, definition.big_endian);
+        if !is_invalid(value, field.size, signed.contains(&field.number)) {
+            fields.insert(field.number, value);
+        }
+    }
+    reader.bytes(definition.developer_size)?;
+    Ok(fields)
+}
+
+fn read_definition(reader: &mut Reader, has_developer_data: bool) -> Result<MessageDefinition, ReadError> {
+    let _reserved = reader.u8()?;
+    let big_endian = reader.u8()? == 1;
+    let global_number = read_unsigned(reader.bytes(2)?, big_endian) as u16;
+    let field_count = reader.u8()?;
+    let mut fields = Vec::new();
+    for _ in 0..field_count {
+        let field = reader.bytes(3)?;
+        fields.push(FieldDefinition { number: field[0], size: field[1] as usize });
+    }
+
+    let mut developer_size = 0;
+    if has_developer_data {
+        let developer_count = reader.u8()?;
+        for _ in 0..developer_count {
+            developer_size += reader.bytes(3)?[1] as usize;
+        }
+    }
+
+    Ok(MessageDefinition { big_endian, global_number, fields, developer_size })
+}
+
+fn semicircles_to_degrees(value: u64) -> f64 {
+    (value as u32 as i32) as f64 * (180.0 / 2f64.powi(31))
+}
+
+fn record_to_point(fields: &Fields, timestamp: Option<u32>) -> Option<TrackPoint> {
+    let lat = semicircles_to_degrees(*fields.get(&0)?);
+    let lng = semicircles_to_degrees(*fields.get(&1)?);
+
+    let mut point = TrackPoint::new(lat, lng);
+    // enhanced_altitude (78) is preferred over altitude (2), both: scale 5, offset 500
+    point.ele = fields
+        .get(&78)
+        .or_else(|| fields.get(&2))
+        .map(|altitude| *altitude as f64 / 5.0 - 500.0);
+    point.time = timestamp.and_then(|timestamp| DateTime::<Utc>::from_timestamp(timestamp as i64 + FIT_EPOCH_OFFSET, 0));
+    point.hr = fields.get(&3).map(|hr| *hr as u32);
+    point.cad = fields.get(&4).map(|cad| *cad as u32);
+    point.atemp = fields.get(&13).map(|temperature| *temperature as u8 as i8 as f64);
+    Some(point)
+}
+
+pub fn parse_fit(fullpath: &str, data: &[u8], options: &Options) -> Result<Vec<CoordsFile>, ReadError> {
+    let mut coord_file = CoordsFile::new(fullpath, "");
+    let mut sport: Option<u8> = None;
+    let mut session_sport: Option<u8> = None;
+    let mut segment: Vec<TrackPoint> = Vec::new();
+
+    let mut reader = Reader { data, pos: 0 };
+    // A file can contain several chained FIT files
+    while reader.pos < data.len() {
+        let header_size = reader.u8()? as usize;
+        if header_size < 12 {
+            return Err(ReadError::Fit(format!("invalid header size {}", header_size)));
+        }
+        let header = reader.bytes(header_size - 1)?;
+        if &header[7..11] != b".FIT" {
+            return Err(ReadError::Fit("missing .FIT signature".to_string()));
+        }
+        let data_end = reader.pos + read_unsigned(&header[3..7], false) as usize;
+
+        let mut definitions: HashMap<u8, MessageDefinition> = HashMap::new();
+        let mut last_timestamp: Option<u32> = None;
+        while reader.pos < data_end {
+            let record_header = reader.u8()?;
+
+            let (local_type, mut timestamp) = if record_header & 0x80 != 0 {
+                // compressed timestamp header, only the lowest 5 bits of the time are sent
+                let offset = (record_header & 0x1F) as u32;
+                let timestamp = last_timestamp.map(|last| {
+                    let mut timestamp = (last & !0x1F) + offset;
+                    if offset < last & 0x1F {
+                        timestamp += 0x20;
+                    }
+                    timestamp
+                });
+                ((record_header >> 5) & 0x03, timestamp)
+            } else if record_header & 0x40 != 0 {
+                let definition = read_definition(&mut reader, record_header & 0x20 != 0)?;
+                definitions.insert(record_header & 0x0F, definition);
+                continue;
+            } else {
+                (record_header & 0x0F, None)
+            };
+
+            let definition = definitions
+                .get(&local_type)
+                .ok_or_else(|| ReadError::Fit(format!("data message without definition {}", local_type)))?;
+            let fields = read_fields(&mut reader, definition)?;
+
+            if let Some(value) = fields.get(&253) {
+                timestamp = Some(*value as u32);
+            }
+            if timestamp.is_some() {
+                last_timestamp = timestamp;
+            }
+
+            match definition.global_number {
+                MESG_RECORD => {
+                    if let Some(point) = record_to_point(&fields, timestamp) {
+                        if options.verbose {
+                            println!("Found point {} {}", point.lat, point.lng);
+                        }
+                        segment.push(point);
+                    }
+                }
+                // event 0 = timer, event_type 1 = stop, 4 = stop_all
+                MESG_EVENT if fields.get(&0) == Some(&0) && matches!(fields.get(&1), Some(1) | Some(4)) => {
+                    coord_file.add_segment(std::mem::take(&mut segment));
+                }
+                MESG_SESSION if session_sport.is_none() => {
+                    session_sport = fields.get(&5).map(|sport| *sport as u8);
+                }
+                MESG_SPORT if sport.is_none() => {
+                    sport = fields.get(&0).map(|sport| *sport as u8);
+                }
+                _ => {}
+            }
+        }
+
+        // file CRC
+        reader.bytes(2)?;
+    }
+    coord_file.add_segment(segment);
+
+    if coord_file.segments.is_empty() {
+        return Err(ReadError::MissingTrack);
+    }
+
+    coord_file.trk_type = match session_sport.or(sport) {
+        Some(sport) => match SPORTS.get(sport as usize) {
+            Some(name) => name.to_string(),
+            None => format!("sport_{}", sport),
+        },
+        None => "".to_string(),
+    };
+    if options.verbose {
+        println!("Found session sport {}", coord_file.trk_type);
+    }
+
+    Ok(vec![coord_file])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // field number, size
+    type FieldSpec = (u8, u8);
+
+    fn definition(local_type: u8, big_endian: bool, global_number: u16, fields: &[FieldSpec], developer_sizes: &[u8]) -> Vec<u8> {
+        let developer_flag = if developer_sizes.is_empty() { 0 } else { 0x20 };
+        let mut bytes = vec![0x40 | developer_flag | local_type, 0, big_endian as u8];
+        if big_endian {
+            bytes.extend(global_number.to_be_bytes());
+        } else {
+            bytes.extend(global_number.to_le_bytes());
+        }
+        bytes.push(fields.len() as u8);
+        for (number, size) in fields {
+            // the base type is not used by the reader
+            bytes.extend([*number, *size, 0]);
+        }
+        if !developer_sizes.is_empty() {
+            bytes.push(developer_sizes.len() as u8);
+            for (index, size) in developer_sizes.iter().enumerate() {
+                bytes.extend([index as u8, *size, 0]);
+            }
+        }
+        bytes
+    }
+
+    fn value(value: u64, size: usize, big_endian: bool) -> Vec<u8> {
+        let bytes = value.to_le_bytes()[..size].to_vec();
+        if big_endian { bytes.into_iter().rev().collect() } else { bytes }
+    }
+
+    fn semicircles(degrees: f64) -> u64 {
+        (degrees * 2f64.powi(31) / 180.0) as i32 as u32 as u64
+    }
+
+    fn fit_file(messages: &[Vec<u8>]) -> Vec<u8> {
+        let data: Vec<u8> = messages.concat();
+        let mut bytes = vec![14, 0x10, 0, 0];
+        bytes.extend((data.len() as u32).to_le_bytes());
+        bytes.extend(b".FIT");
+        bytes.extend([0, 0]);
+        bytes.extend(data);
+        // file CRC, not checked
+        bytes.extend([0, 0]);
+        bytes
+    }
+
+    const RECORD_FIELDS: [FieldSpec; 4] = [(253, 4), (0, 4), (1, 4), (2, 2)];
+
+    fn record(local_type: u8, timestamp: u32, lat: f64, lng: f64, big_endian: bool) -> Vec<u8> {
+        let mut bytes = vec![local_type];
+        bytes.extend(value(timestamp as u64, 4, big_endian));
+        bytes.extend(value(semicircles(lat), 4, big_endian));
+        bytes.extend(value(semicircles(lng), 4, big_endian));
+        // 100 m: (100 + 500) * 5
+        bytes.extend(value(3000, 2, big_endian));
+        bytes
+    }
+
+    fn parse(data: &[u8]) -> CoordsFile {
+        let mut coord_files = parse_fit("test.fit", data, &Options::default()).unwrap_or_else(|e| panic!("{}", e));
+        assert_eq!(coord_files.len(), 1);
+        coord_files.remove(0)
+    }
+
+    fn fit_time(timestamp: i64) -> Option<DateTime<Utc>> {
+        DateTime::<Utc>::from_timestamp(timestamp + FIT_EPOCH_OFFSET, 0)
+    }
+
+    #[test]
+    fn reads_record_and_session_sport() {
+        let data = fit_file(&[
+            definition(0, false, MESG_RECORD, &RECORD_FIELDS, &[]),
+            record(0, 1000, 51.5, -0.25, false),
+            definition(1, false, MESG_SESSION, &[(5, 1)], &[]),
+            vec![1, 2],
+        ]);
+        let coord_file = parse(&data);
+        assert_eq!(coord_file.trk_type, "cycling");
+        assert_eq!(coord_file.segments.len(), 1);
+        let point = &coord_file.segments[0][0];
+        assert!((point.lat - 51.5).abs() < 1e-6);
+        assert!((point.lng + 0.25).abs() < 1e-6);
+        assert_eq!(point.ele, Some(100.0));
+        assert_eq!(point.time, fit_time(1000));
+    }
+
+    #[test]
+    fn reads_big_endian_definition() {
+        let data = fit_file(&[
+            definition(0, true, MESG_RECORD, &RECORD_FIELDS, &[]),
+            record(0, 1000, 51.5, 7.25, true),
+        ]);
+        let point = &parse(&data).segments[0][0];
+        assert!((point.lat - 51.5).abs() < 1e-6);
+        assert!((point.lng - 7.25).abs() < 1e-6);
+        assert_eq!(point.ele, Some(100.0));
+        assert_eq!(point.time, fit_time(1000));
+    }
+
+    #[test]
+    fn missing_sport_leaves_type_empty_and_invalid_values_are_skipped() {
+        let mut invalid_altitude = record(0, 1000, 51.5, 7.25, false);
+        let length = invalid_altitude.len();
+        invalid_altitude[length - 2..].copy_from_slice(&[0xFF, 0xFF]);
+        let data = fit_file(&[definition(0, false, MESG_RECORD, &RECORD_FIELDS, &[]), invalid_altitude]);
+        let coord_file = parse(&data);
+        assert_eq!(coord_file.trk_type, "");
+        assert_eq!(coord_file.segments[0][0].ele, None);
+    }
+
+    #[test]
+    fn compressed_timestamp_crosses_wrap() {
+        // 0x3E ends in 0x1E, an offset of 0x01 lies in the next 32 second window
+        let mut compressed = vec![0x80 | 0x01];
+        compressed.extend(value(semicircles(51.6), 4, false));
+        compressed.extend(value(semicircles(7.0), 4, false));
+        let data = fit_file(&[
+            definition(1, false, MESG_RECORD, &RECORD_FIELDS, &[]),
+            record(1, 0x3E, 51.5, 7.0, false),
+            definition(0, false, MESG_RECORD, &[(0, 4), (1, 4)], &[]),
+            compressed,
+        ]);
+        let coord_file = parse(&data);
+        let points = &coord_file.segments[0];
+        assert_eq!(points.len(), 2);
+        assert_eq!(points[1].time, fit_time(0x41));
+        assert!((points[1].lat - 51.6).abs() < 1e-6);
+    }
+
+    #[test]
+    fn skips_developer_fields() {
+        let with_developer_data = |timestamp: u32, lat: f64| {
+            let mut bytes = record(0, timestamp, lat, 7.0, false);
+            bytes.extend([0xAA, 0xBB, 0xCC]);
+            bytes
+        };
+        let data = fit_file(&[
+            definition(0, false, MESG_RECORD, &RECORD_FIELDS, &[2, 1]),
+            with_developer_data(1000, 51.5),
+            with_developer_data(1001, 51.6),
+        ]);
+        let points = &parse(&data).segments[0];
+        assert_eq!(points.len(), 2);
+        assert!((points[1].lat - 51.6).abs() < 1e-6);
+        assert_eq!(points[1].time, fit_time(1001));
+    }
+
+    #[test]
+    fn timer_stop_splits_segments() {
+        let data = fit_file(&[
+            definition(0, false, MESG_RECORD, &RECORD_FIELDS, &[]),
+            record(0, 1000, 51.5, 7.0, false),
+            record(0, 1001, 51.6, 7.0, false),
+            // event timer (0), event_type stop_all (4)
+            definition(1, false, MESG_EVENT, &[(0, 1), (1, 1)], &[]),
+            vec![1, 0, 4],
+            record(0, 2000, 51.7, 7.0, false),
+        ]);
+        let coord_file = parse(&data);
+        assert_eq!(coord_file.segments.len(), 2);
+        assert_eq!(coord_file.segments[0].len(), 2);
+        assert_eq!(coord_file.segments[1].len(), 1);
+    }
+
+    #[test]
+    fn reads_chained_files() {
+        let first = fit_file(&[definition(0, false, MESG_RECORD, &RECORD_FIELDS, &[]), record(0, 1000, 51.5, 7.0, false)]);
+        let second = fit_file(&[definition(0, false, MESG_RECORD, &RECORD_FIELDS, &[]), record(0, 1001, 51.6, 7.0, false)]);
+        let coord_file = parse(&[first, second].concat());
+        assert_eq!(coord_file.points().count(), 2);
+    }
+
+    #[test]
+    fn truncated_file_is_an_error() {
+        let data = fit_file(&[definition(0, false, MESG_RECORD, &RECORD_FIELDS, &[]), record(0, 1000, 51.5, 7.0, false)]);
+        for length in [5, 20, data.len() - 5] {
+            let result = parse_fit("test.fit", &data[..length], &Options::default());
+            assert!(matches!(result, Err(ReadError::Fit(_))), "length {}", length);
+        }
+    }
+
+    #[test]
+    fn missing_signature_is_an_error() {
+        let mut data = fit_file(&[]);
+        data[8] = b'X';
+        assert!(matches!(parse_fit("test.fit", &data, &Options::default()), Err(ReadError::Fit(_))));
+    }
+}
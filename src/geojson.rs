@@ -0,0 +1,149 @@
+// GeoJSON (*.geojson) reader
+//
+// LineString and MultiLineString geometries, either bare or inside a Feature / FeatureCollection.
+// The activity type is read from the feature property named by --type-property.
+// Polygon and MultiPolygon geometries are read as custom group regions.
+
+use serde_json::Value;
+
+use crate::CoordsFile;
+use crate::LatLng;
+use crate::Options;
+use crate::Polygon;
+use crate::ReadError;
+use crate::TrackPoint;
+
+fn read_position(position: &Value) -> Result<TrackPoint, ReadError> {
+    // [lng, lat] or [lng, lat, ele]
+    let values = position
+        .as_array()
+        .filter(|values| values.len() >= 2)
+        .ok_or_else(|| ReadError::BadCoordinate(position.to_string()))?;
+    let number = |index: usize| {
+        values[index]
+            .as_f64()
+            .ok_or_else(|| ReadError::BadCoordinate(position.to_string()))
+    };
+
+    let mut point = TrackPoint::new(number(1)?, number(0)?);
+    point.ele = values.get(2).and_then(|ele| ele.as_f64());
+    Ok(point)
+}
+
+fn read_line(line: &Value) -> Result<Vec<TrackPoint>, ReadError> {
+    match line.as_array() {
+        Some(positions) => positions.iter().map(read_position).collect(),
+        None => Err(ReadError::BadCoordinate(line.to_string())),
+    }
+}
+
+fn read_geometry(geometry: &Value, coord_file: &mut CoordsFile) -> Result<(), ReadError> {
+    let coordinates = &geometry["coordinates"];
+    match geometry["type"].as_str() {
+        Some("LineString") => coord_file.add_segment(read_line(coordinates)?),
+        Some("MultiLineString") => {
+            for line in coordinates.as_array().into_iter().flatten() {
+                coord_file.add_segment(read_line(line)?);
+            }
+        }
+        Some("GeometryCollection") => {
+            for geometry in geometry["geometries"].as_array().into_iter().flatten() {
+                read_geometry(geometry, coord_file)?;
+            }
+        }
+        // Points, polygons, ... are not tracks
+        _ => {}
+    }
+    Ok(())
+}
+
+pub fn parse_geojson(fullpath: &str, text: &str, options: &Options) -> Result<Vec<CoordsFile>, ReadError> {
+    let root: Value = serde_json::from_str(text)?;
+
+    let features: Vec<&Value> = match root["type"].as_str() {
+        Some("FeatureCollection") => root["features"].as_array().into_iter().flatten().collect(),
+        Some("Feature") => vec![&root],
+        _ => vec![],
+    };
+
+    let mut coord_file = CoordsFile::new(fullpath, "");
+    if features.is_empty() {
+        read_geometry(&root, &mut coord_file)?;
+    }
+    for feature in &features {
+        let before = coord_file.segments.len();
+        read_geometry(&feature["geometry"], &mut coord_file)?;
+
+        // first track feature with a type wins
+        if coord_file.trk_type.is_empty()
+            && coord_file.segments.len() > before
+            && let Some(trk_type) = feature["properties"][options.type_property_str.as_str()].as_str()
+        {
+            coord_file.trk_type = trk_type.trim().to_string();
+        }
+    }
+
+    if coord_file.segments.is_empty() {
+        return Err(ReadError::MissingTrack);
+    }
+    if options.verbose {
+        println!("Found feature type {}", coord_file.trk_type);
+    }
+
+    Ok(vec![coord_file])
+}
+
+fn read_ring(ring: &Value) -> Result<Vec<LatLng>, ReadError> {
+    Ok(read_line(ring)?
+        .iter()
+        .map(|point| LatLng { lat: point.lat, lng: point.lng })
+        .collect())
+}
+
+fn read_polygon(rings: &Value) -> Result<Polygon, ReadError> {
+    // first ring is the exterior, all others are holes
+    let mut rings: Vec<Vec<LatLng>> = rings
+        .as_array()
+        .into_iter()
+        .flatten()
+        .map(read_ring)
+        .collect::<Result<_, _>>()?;
+    if rings.is_empty() {
+        return Err(ReadError::BadCoordinate("polygon without rings".to_string()));
+    }
+    let exterior = rings.remove(0);
+    Ok(Polygon::new(exterior, rings))
+}
+
+fn read_polygon_geometry(geometry: &Value, polygons: &mut Vec<Polygon>) -> Result<(), ReadError> {
+    let coordinates = &geometry["coordinates"];
+    match geometry["type"].as_str() {
+        Some("Polygon") => polygons.push(read_polygon(coordinates)?),
+        Some("MultiPolygon") => {
+            for polygon in coordinates.as_array().into_iter().flatten() {
+                polygons.push(read_polygon(polygon)?);
+            }
+        }
+        Some("GeometryCollection") => {
+            for geometry in geometry["geometries"].as_array().into_iter().flatten() {
+                read_polygon_geometry(geometry, polygons)?;
+            }
+        }
+        Some("Feature") => read_polygon_geometry(&geometry["geometry"], polygons)?,
+        Some("FeatureCollection") => {
+            for feature in geometry["features"].as_array().into_iter().flatten() {
+                read_polygon_geometry(feature, polygons)?;
+            }
+        }
+        _ => {}
+    }
+    Ok(())
+}
+
+// All Polygon and MultiPolygon geometries of a GeoJSON document, used for custom group regions
+pub fn parse_polygons(text: &str) -> Result<Vec<Polygon>, ReadError> {
+    let root: Value = serde_json::from_str(text)?;
+    let mut polygons = Vec::new();
+    read_polygon_geometry(&root, &mut polygons)?;
+    Ok(polygons)
+}
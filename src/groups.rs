@@ -0,0 +1,317 @@
+// Custom groups file (-c)
+//
+// TOML (*.toml), one [[group]] table per group:
+//
+// [[group]]
+// name = "holiday"
+// type = "cycling"                 # optional, empty matches every type
+// bbox = [45.8, 10.0, 46.9, 12.5]  # min_lat, min_lng, max_lat, max_lng
+// polygon = "valley.geojson"       # instead of bbox, relative to the groups file
+// min_id = 100                     # optional id range
+// max_id = 200
+// min_fraction = 0.5               # optional, default from [groups] in --config
+// measure = "points"
+// from = "2021-03"                 # optional start date range, YYYY-MM or YYYY-MM-DD, both inclusive
+// to = "2022-08"
+// weekdays = ["mon", "tue"]        # optional
+// hours = [6, 10]                  # optional, start hour inclusive, end hour exclusive
+// time_zone = "Europe/Berlin"      # optional, IANA name or fixed offset like "+01:00", default from [groups] in --config
+// priority = 10                    # optional, highest matching priority wins, later groups win ties
+//
+// Everything else is read as the legacy format, one group per line:
+// name;type;min_lat;min_lng;max_lat;max_lng;min_id;max_id
+// name;type;region.geojson;min_id;max_id
+
+use std::fs;
+use std::ops::Range;
+use std::path::Path;
+
+use chrono::Months;
+use chrono::NaiveDate;
+use chrono::Weekday;
+use serde::Deserialize;
+use toml::Spanned;
+
+use crate::CustomGroup;
+use crate::LatLng;
+use crate::Options;
+use crate::Region;
+use crate::config::GroupMeasure;
+use crate::config::GroupTimeZone;
+use crate::read_region_file;
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct GroupsFile {
+    #[serde(default)]
+    group: Vec<Spanned<GroupEntry>>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct GroupEntry {
+    name: Spanned<String>,
+    #[serde(rename = "type", default)]
+    trk_type: String,
+    bbox: Option<Spanned<Vec<f64>>>,
+    polygon: Option<Spanned<String>>,
+    min_id: Option<Spanned<i64>>,
+    max_id: Option<Spanned<i64>>,
+    min_fraction: Option<Spanned<f64>>,
+    measure: Option<GroupMeasure>,
+    from: Option<Spanned<String>>,
+    to: Option<Spanned<String>>,
+    weekdays: Option<Spanned<Vec<String>>>,
+    hours: Option<Spanned<Vec<u32>>>,
+    time_zone: Option<Spanned<String>>,
+    #[serde(default)]
+    priority: i64,
+}
+
+fn line_of(text: &str, span: Range<usize>) -> usize {
+    text[..span.start.min(text.len())].matches('\n').count() + 1
+}
+
+fn bbox_region(bbox: &[f64]) -> Result<Region, String> {
+    let [min_lat, min_lng, max_lat, max_lng] = bbox else {
+        return Err(format!("bbox needs 4 values (min_lat, min_lng, max_lat, max_lng), found {}", bbox.len()));
+    };
+    for lat in [min_lat, max_lat] {
+        if !(-90.0..=90.0).contains(lat) {
+            return Err(format!("latitude {} out of range", lat));
+        }
+    }
+    for lng in [min_lng, max_lng] {
+        if !(-180.0..=180.0).contains(lng) {
+            return Err(format!("longitude {} out of range", lng));
+        }
+    }
+    if min_lat > max_lat || min_lng > max_lng {
+        return Err("bbox minimum is larger than its maximum".to_string());
+    }
+    Ok(Region::Rect(
+        LatLng { lat: *min_lat, lng: *min_lng },
+        LatLng { lat: *max_lat, lng: *max_lng },
+    ))
+}
+
+// YYYY-MM or YYYY-MM-DD, the end of the range is returned exclusive
+fn parse_date(text: &str, end: bool) -> Result<NaiveDate, String> {
+    if let Ok(date) = NaiveDate::parse_from_str(text, "%Y-%m-%d") {
+        return Ok(if end { date.succ_opt().unwrap_or(date) } else { date });
+    }
+    match NaiveDate::parse_from_str(&format!("{}-01", text), "%Y-%m-%d") {
+        Ok(date) if end => Ok(date.checked_add_months(Months::new(1)).unwrap_or(date)),
+        Ok(date) => Ok(date),
+        Err(_) => Err(format!("invalid date '{}', expected YYYY-MM or YYYY-MM-DD", text)),
+    }
+}
+
+fn parse_hours(hours: &[u32]) -> Result<(u32, u32), String> {
+    match hours {
+        [start, end] if *start < 24 && *end <= 24 && start != end => Ok((*start, *end)),
+        _ => Err("hours needs [start, end], start hour 0 to 23, end hour 0 to 24".to_string()),
+    }
+}
+
+fn check_id_range(min_id: i64, max_id: i64) -> Result<(), String> {
+    if min_id != -1 && max_id != -1 && min_id > max_id {
+        return Err(format!("min_id {} is larger than max_id {}", min_id, max_id));
+    }
+    Ok(())
+}
+
+fn read_toml_groups(path: &str, text: &str, options: &Options) -> Result<Vec<CustomGroup>, Vec<String>> {
+    let base_path = Path::new(path).parent().unwrap_or(Path::new(""));
+    let groups_file: GroupsFile = toml::from_str(text).map_err(|e| {
+        let line = e.span().map_or(0, |span| line_of(text, span));
+        vec![format!("{}:{}: {}", path, line, e.message())]
+    })?;
+
+    let mut errors: Vec<String> = Vec::new();
+    let mut custom_groups: Vec<CustomGroup> = Vec::new();
+    for entry in groups_file.group {
+        let group_line = line_of(text, entry.span());
+        let entry = entry.into_inner();
+        let mut error = |span: Option<Range<usize>>, message: String| {
+            let line = span.map_or(group_line, |span| line_of(text, span));
+            errors.push(format!("{}:{}: group {}: {}", path, line, entry.name.get_ref(), message));
+        };
+
+        if entry.name.get_ref().trim().is_empty() {
+            error(Some(entry.name.span()), "name must not be empty".to_string());
+        }
+
+        let region = match (&entry.bbox, &entry.polygon) {
+            (Some(bbox), None) => bbox_region(bbox.get_ref()).map_err(|e| error(Some(bbox.span()), e)).ok(),
+            (None, Some(polygon)) => read_region_file(&base_path.join(polygon.get_ref()))
+                .map_err(|e| error(Some(polygon.span()), format!("region file {}: {}", polygon.get_ref(), e)))
+                .ok(),
+            _ => {
+                error(None, "exactly one of bbox and polygon is required".to_string());
+                None
+            }
+        };
+
+        let (min_id, max_id) = match (&entry.min_id, &entry.max_id) {
+            (Some(min_id), Some(max_id)) => (*min_id.get_ref(), *max_id.get_ref()),
+            (None, None) => (-1, -1),
+            (Some(id), None) | (None, Some(id)) => {
+                error(Some(id.span()), "min_id and max_id have to be given together".to_string());
+                (-1, -1)
+            }
+        };
+        if let Err(e) = check_id_range(min_id, max_id) {
+            error(entry.min_id.as_ref().map(|id| id.span()), e);
+        }
+
+        let min_fraction = entry.min_fraction.as_ref().map_or(options.config.groups.min_fraction, |f| *f.get_ref());
+        if !(0.0..=1.0).contains(&min_fraction) {
+            error(entry.min_fraction.as_ref().map(|f| f.span()), format!("min_fraction {} is not between 0 and 1", min_fraction));
+        }
+
+        let start_date = entry
+            .from
+            .as_ref()
+            .and_then(|from| parse_date(from.get_ref(), false).map_err(|e| error(Some(from.span()), e)).ok());
+        let end_date = entry
+            .to
+            .as_ref()
+            .and_then(|to| parse_date(to.get_ref(), true).map_err(|e| error(Some(to.span()), e)).ok());
+        if let (Some(start_date), Some(end_date)) = (start_date, end_date)
+            && start_date >= end_date
+        {
+            error(entry.from.as_ref().map(|from| from.span()), "from is after to".to_string());
+        }
+
+        let mut weekdays: Vec<Weekday> = Vec::new();
+        if let Some(weekday_names) = &entry.weekdays {
+            for weekday_name in weekday_names.get_ref() {
+                match weekday_name.parse::<Weekday>() {
+                    Ok(weekday) => weekdays.push(weekday),
+                    Err(_) => error(Some(weekday_names.span()), format!("invalid weekday '{}'", weekday_name)),
+                }
+            }
+        }
+
+        let hours = entry
+            .hours
+            .as_ref()
+            .and_then(|hours| parse_hours(hours.get_ref()).map_err(|e| error(Some(hours.span()), e)).ok());
+
+        let time_zone = match &entry.time_zone {
+            Some(time_zone) => GroupTimeZone::parse(time_zone.get_ref()).unwrap_or_else(|e| {
+                error(Some(time_zone.span()), format!("invalid time_zone '{}': {}", time_zone.get_ref(), e));
+                options.config.groups.time_zone()
+            }),
+            None => options.config.groups.time_zone(),
+        };
+
+        if let Some(region) = region {
+            custom_groups.push(CustomGroup {
+                name: entry.name.into_inner(),
+                trk_type: entry.trk_type,
+                region,
+                min_id,
+                max_id,
+                min_fraction,
+                measure: entry.measure.unwrap_or(options.config.groups.measure),
+                start_date,
+                end_date,
+                weekdays,
+                hours,
+                time_zone,
+                priority: entry.priority,
+            });
+        }
+    }
+
+    if !errors.is_empty() {
+        return Err(errors);
+    }
+    Ok(custom_groups)
+}
+
+fn parse_legacy_line(line: &str, base_path: &Path, options: &Options) -> Result<CustomGroup, String> {
+    let parts: Vec<&str> = line.split(";").map(|part| part.trim()).collect();
+    let number = |index: usize, field: &str| {
+        parts[index]
+            .parse::<f64>()
+            .map_err(|_| format!("invalid number '{}' for {}", parts[index], field))
+    };
+    let id = |index: usize, field: &str| {
+        parts[index]
+            .parse::<i64>()
+            .map_err(|_| format!("invalid id '{}' for {}", parts[index], field))
+    };
+
+    let region = match parts.len() {
+        8 => bbox_region(&[
+            number(2, "min_lat")?,
+            number(3, "min_lng")?,
+            number(4, "max_lat")?,
+            number(5, "max_lng")?,
+        ])?,
+        5 => read_region_file(&base_path.join(parts[2])).map_err(|e| format!("region file {}: {}", parts[2], e))?,
+        count => return Err(format!("expected 8 or 5 ';' separated fields, found {}", count)),
+    };
+    if parts[0].is_empty() {
+        return Err("name must not be empty".to_string());
+    }
+
+    let min_id = id(parts.len() - 2, "min_id")?;
+    let max_id = id(parts.len() - 1, "max_id")?;
+    check_id_range(min_id, max_id)?;
+
+    Ok(CustomGroup {
+        name: parts[0].to_string(),
+        trk_type: parts[1].to_string(),
+        region,
+        min_id,
+        max_id,
+        min_fraction: options.config.groups.min_fraction,
+        measure: options.config.groups.measure,
+        start_date: None,
+        end_date: None,
+        weekdays: vec![],
+        hours: None,
+        time_zone: options.config.groups.time_zone(),
+        priority: 0,
+    })
+}
+
+fn read_legacy_groups(path: &str, text: &str, options: &Options) -> Result<Vec<CustomGroup>, Vec<String>> {
+    // region files are relative to the custom groups file
+    let base_path = Path::new(path).parent().unwrap_or(Path::new(""));
+
+    let mut errors: Vec<String> = Vec::new();
+    let mut custom_groups: Vec<CustomGroup> = Vec::new();
+    for (index, line) in text.lines().enumerate() {
+        if options.verbose {
+            println!("line: {}", line);
+        }
+        if line.trim().is_empty() || line.trim_start().starts_with('#') {
+            continue;
+        }
+
+        match parse_legacy_line(line, base_path, options) {
+            Ok(custom_group) => custom_groups.push(custom_group),
+            Err(e) => errors.push(format!("{}:{}: {}", path, index + 1, e)),
+        }
+    }
+
+    if !errors.is_empty() {
+        return Err(errors);
+    }
+    Ok(custom_groups)
+}
+
+// All groups of the file, or every problem found, prefixed with file:line
+pub fn read_groups_file(path: &str, options: &Options) -> Result<Vec<CustomGroup>, Vec<String>> {
+    let text = fs::read_to_string(path).map_err(|e| vec![format!("{}: {}", path, e)])?;
+    if path.ends_with(".toml") {
+        read_toml_groups(path, &text, options)
+    } else {
+        read_legacy_groups(path, &text, options)
+    }
+}
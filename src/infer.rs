@@ -0,0 +1,53 @@
+// Activity type inference from point timestamps
+//
+// The speed between consecutive points (within a segment) is collected, standing still is ignored,
+// and the configured percentile of the remaining speeds is compared to the thresholds.
+
+use crate::CoordsFile;
+use crate::config::InferConfig;
+
+pub fn moving_speed_kmh(coord_file: &CoordsFile, infer_config: &InferConfig) -> Option<f64> {
+    let mut speeds: Vec<f64> = Vec::new();
+    for segment in &coord_file.segments {
+        for pair in segment.windows(2) {
+            let (Some(start), Some(end)) = (pair[0].time, pair[1].time) else {
+                continue;
+            };
+            let seconds = (end - start).num_milliseconds() as f64 / 1000.0;
+            if seconds <= 0.0 {
+                continue;
+            }
+            let speed = pair[0].distance_m(&pair[1]) / seconds * 3.6;
+            if speed >= infer_config.min_moving_kmh {
+                speeds.push(speed);
+            }
+        }
+    }
+
+    if speeds.is_empty() {
+        return None;
+    }
+    speeds.sort_by(|a, b| a.total_cmp(b));
+    let index = ((speeds.len() - 1) as f64 * infer_config.percentile / 100.0).round() as usize;
+    Some(speeds[index])
+}
+
+// Every type classify can return
+pub const INFERRED_TYPES: [&str; 4] = ["walking", "running", "cycling", "driving"];
+
+pub fn classify(speed_kmh: f64, infer_config: &InferConfig) -> &'static str {
+    if speed_kmh <= infer_config.walking_max_kmh {
+        "walking"
+    } else if speed_kmh <= infer_config.running_max_kmh {
+        "running"
+    } else if speed_kmh <= infer_config.cycling_max_kmh {
+        "cycling"
+    } else {
+        "driving"
+    }
+}
+
+pub fn infer_type(coord_file: &CoordsFile, infer_config: &InferConfig) -> Option<(&'static str, f64)> {
+    let speed = moving_speed_kmh(coord_file, infer_config)?;
+    Some((classify(speed, infer_config), speed))
+}
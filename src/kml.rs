@@ -0,0 +1,117 @@
+// Google Earth KML (*.kml) reader
+//
+// Placemarks with gx:Track (gx:MultiTrack) or LineString (MultiGeometry) geometries.
+// The activity type is read from the ExtendedData entry named by --type-property.
+
+use chrono::DateTime;
+use chrono::Utc;
+
+use crate::CoordsFile;
+use crate::Options;
+use crate::ReadError;
+use crate::TrackPoint;
+use crate::parse_time;
+
+fn read_tuple(tuple: &str, separator: char) -> Result<TrackPoint, ReadError> {
+    // lng,lat[,alt] in coordinates, "lng lat [alt]" in gx:coord
+    let values: Vec<&str> = tuple.split(separator).filter(|value| !value.is_empty()).collect();
+    if values.len() < 2 {
+        return Err(ReadError::BadCoordinate(tuple.to_string()));
+    }
+    let number = |index: usize| {
+        values[index]
+            .parse::<f64>()
+            .map_err(|_| ReadError::BadCoordinate(tuple.to_string()))
+    };
+
+    let mut point = TrackPoint::new(number(1)?, number(0)?);
+    point.ele = values.get(2).and_then(|ele| ele.parse::<f64>().ok());
+    Ok(point)
+}
+
+fn read_line_string(line_string: roxmltree::Node) -> Result<Vec<TrackPoint>, ReadError> {
+    let coordinates = line_string
+        .children()
+        .find(|n| n.has_tag_name("coordinates"))
+        .and_then(|n| n.text())
+        .unwrap_or("");
+    coordinates
+        .split_whitespace()
+        .map(|tuple| read_tuple(tuple, ','))
+        .collect()
+}
+
+fn read_gx_track(track: roxmltree::Node) -> Result<Vec<TrackPoint>, ReadError> {
+    // <when> and <gx:coord> are listed separately, matched by position
+    let times: Vec<Option<DateTime<Utc>>> = track
+        .children()
+        .filter(|n| n.has_tag_name("when"))
+        .map(|when| {
+            when.text().and_then(parse_time)
+        })
+        .collect();
+
+    let mut segment = Vec::new();
+    for (index, coord) in track.children().filter(|n| n.has_tag_name("coord")).enumerate() {
+        let mut point = read_tuple(coord.text().unwrap_or("").trim(), ' ')?;
+        point.time = times.get(index).copied().flatten();
+        segment.push(point);
+    }
+    Ok(segment)
+}
+
+fn read_type(placemark: roxmltree::Node, type_property: &str) -> Option<String> {
+    let extended_data = placemark.children().find(|n| n.has_tag_name("ExtendedData"))?;
+    for data in extended_data.descendants() {
+        // <Data name=".."><value>..</value></Data> or <SchemaData><SimpleData name="..">..</SimpleData>
+        if data.attribute("name") != Some(type_property) {
+            continue;
+        }
+        let value = if data.has_tag_name("Data") {
+            data.children().find(|n| n.has_tag_name("value")).and_then(|n| n.text())
+        } else {
+            data.text()
+        };
+        if let Some(value) = value.map(|value| value.trim()).filter(|value| !value.is_empty()) {
+            return Some(value.to_string());
+        }
+    }
+    None
+}
+
+pub fn parse_kml(fullpath: &str, text: &str, options: &Options) -> Result<Vec<CoordsFile>, ReadError> {
+    let opt = roxmltree::ParsingOptions {
+        allow_dtd: true,
+        ..roxmltree::ParsingOptions::default()
+    };
+    let doc = roxmltree::Document::parse_with_options(text, opt)?;
+
+    let mut coord_file = CoordsFile::new(fullpath, "");
+    for placemark in doc.descendants().filter(|n| n.has_tag_name("Placemark")) {
+        let before = coord_file.segments.len();
+        for geometry in placemark.descendants() {
+            if geometry.has_tag_name("Track") {
+                coord_file.add_segment(read_gx_track(geometry)?);
+            } else if geometry.has_tag_name("LineString") {
+                coord_file.add_segment(read_line_string(geometry)?);
+            }
+        }
+
+        // first track placemark with a type wins
+        if coord_file.trk_type.is_empty()
+            && coord_file.segments.len() > before
+            && let Some(trk_type) = read_type(placemark, &options.type_property_str)
+        {
+            coord_file.trk_type = trk_type;
+        }
+    }
+
+    if coord_file.segments.is_empty() {
+        return Err(ReadError::MissingTrack);
+    }
+    if options.verbose {
+        println!("Found placemark type {}", coord_file.trk_type);
+    }
+
+    Ok(vec![coord_file])
+}
@@ -1,28 +1,191 @@
+use std::cmp::Ordering;
 use std::collections::HashMap;
 use std::collections::HashSet;
+use std::fmt;
 use std::fs;
 use std::fs::File;
 use std::io::BufRead;
 use std::io::BufReader;
+use std::io::Read;
 use std::io::Write;
 use std::path::Path;
 
+mod archive;
+mod config;
+mod fit;
+mod geojson;
+mod groups;
+mod infer;
+mod kml;
+mod novelty;
+mod simplify;
+mod tcx;
+
 use argparse::ArgumentParser;
+use argparse::Collect;
 use argparse::Store;
 use argparse::StoreTrue;
-
-#[derive(PartialEq)]
+use chrono::DateTime;
+use chrono::Datelike;
+use chrono::NaiveDate;
+use chrono::Timelike;
+use chrono::Utc;
+use chrono::Weekday;
+use flate2::read::GzDecoder;
+use globset::Glob;
+use globset::GlobSet;
+use globset::GlobSetBuilder;
+use rayon::prelude::*;
+use walkdir::WalkDir;
+
+#[derive(Clone, PartialEq)]
 struct LatLng {
     lat: f64,
     lng: f64,
 }
 
+#[derive(Clone)]
+struct TrackPoint {
+    lat: f64,
+    lng: f64,
+    ele: Option<f64>,
+    time: Option<DateTime<Utc>>,
+    // Garmin TrackPointExtension values
+    hr: Option<u32>,
+    cad: Option<u32>,
+    atemp: Option<f64>,
+}
+
+impl TrackPoint {
+    fn new(lat: f64, lng: f64) -> TrackPoint {
+        TrackPoint { lat, lng, ele: None, time: None, hr: None, cad: None, atemp: None }
+    }
+
+    fn same_position(&self, other: &TrackPoint) -> bool {
+        self.lat == other.lat && self.lng == other.lng
+    }
+
+    fn latlng(&self) -> LatLng {
+        LatLng { lat: self.lat, lng: self.lng }
+    }
+
+    fn distance_m(&self, other: &TrackPoint) -> f64 {
+        haversine_m(self.lat, self.lng, other.lat, other.lng)
+    }
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum PointField {
+    Lat,
+    Lng,
+    Ele,
+    Time,
+    Hr,
+    Cad,
+    Atemp,
+}
+
+fn parse_point_fields(fields_str: &str) -> Option<Vec<PointField>> {
+    fields_str
+        .split(',')
+        .map(|field| match field.trim() {
+            "lat" => Some(PointField::Lat),
+            "lng" => Some(PointField::Lng),
+            "ele" => Some(PointField::Ele),
+            "t" => Some(PointField::Time),
+            "hr" => Some(PointField::Hr),
+            "cad" => Some(PointField::Cad),
+            "atemp" => Some(PointField::Atemp),
+            _ => None,
+        })
+        .collect()
+}
+
+#[derive(Clone)]
 struct CoordsFile {
     name: String,
     trk_type: String,
-    coords: Vec<LatLng>,
+    // every trkseg is kept as its own polyline, so gaps are not bridged
+    segments: Vec<Vec<TrackPoint>>,
     min: LatLng,
     max: LatLng,
+    // from the file metadata, see start_time() for the fallback
+    metadata_time: Option<DateTime<Utc>>,
+    // set by categorize from the file name, the readers may set it from the file metadata
+    id: Option<i64>,
+    // base of the output file and variable name, from the path relative to the input directory
+    output_name: String,
+    // written to several groups in the tags mode, the group is added to the variable name
+    multi_group: bool,
+}
+
+impl CoordsFile {
+    fn new(name: &str, trk_type: &str) -> CoordsFile {
+        CoordsFile {
+            name: name.to_string(),
+            trk_type: trk_type.to_string(),
+            segments: vec![],
+            min: LatLng { lat: f64::MAX, lng: f64::MAX },
+            max: LatLng { lat: 0.0, lng: 0.0 },
+            metadata_time: None,
+            id: None,
+            output_name: String::new(),
+            multi_group: false,
+        }
+    }
+
+    fn start_time(&self) -> Option<DateTime<Utc>> {
+        self.metadata_time.or_else(|| self.points().find_map(|point| point.time))
+    }
+
+    fn add_segment(&mut self, segment: Vec<TrackPoint>) {
+        if segment.is_empty() {
+            return;
+        }
+
+        // Save max/min
+        for point in &segment {
+            if point.lat > self.max.lat {
+                self.max.lat = point.lat;
+            }
+            if point.lng > self.max.lng {
+                self.max.lng = point.lng;
+            }
+            if point.lat < self.min.lat {
+                self.min.lat = point.lat;
+            }
+            if point.lng < self.min.lng {
+                self.min.lng = point.lng;
+            }
+        }
+        self.segments.push(segment);
+    }
+
+    fn points(&self) -> impl Iterator<Item = &TrackPoint> {
+        self.segments.iter().flatten()
+    }
+
+    fn replace_segments(&mut self, segments: Vec<Vec<TrackPoint>>) {
+        self.segments.clear();
+        self.min = LatLng { lat: f64::MAX, lng: f64::MAX };
+        self.max = LatLng { lat: 0.0, lng: 0.0 };
+        for segment in segments {
+            self.add_segment(segment);
+        }
+    }
+}
+
+struct Waypoint {
+    point: LatLng,
+    name: Option<String>,
+    desc: Option<String>,
+    sym: Option<String>,
+}
+
+struct WaypointFile {
+    name: String,
+    waypoints: Vec<Waypoint>,
+    output_name: String,
 }
 
 fn round_val(value: f64, digits: u32) -> f64 {
@@ -30,28 +193,175 @@ fn round_val(value: f64, digits: u32) -> f64 {
     (value * y).round() / y
 }
 
-fn in_line(a: &LatLng, b: &LatLng, c: &LatLng) -> bool {
-    (a.lat - c.lat) * (c.lng - b.lng) == (c.lat - b.lat) * (a.lng - c.lng)
+// JS number with at most digits decimals, never in exponent notation and without trailing zeros
+fn format_number(value: f64, digits: u32) -> String {
+    if !value.is_finite() {
+        return "null".to_string();
+    }
+    let mut text = format!("{:.*}", digits as usize, value);
+    if text.contains('.') {
+        text.truncate(text.trim_end_matches('0').trim_end_matches('.').len());
+    }
+    if text == "-0" {
+        text = "0".to_string();
+    }
+    text
+}
+
+// Maximum of --precision, more decimals than a f64 coordinate holds make no sense
+const MAX_PRECISION: u32 = 9;
+// Decimals of the other point values: centimetres for the elevation, a tenth degree for the temperature
+const ELE_PRECISION: u32 = 2;
+const ATEMP_PRECISION: u32 = 1;
+
+const EARTH_RADIUS_M: f64 = 6_371_000.0;
+
+fn haversine_m(lat1: f64, lng1: f64, lat2: f64, lng2: f64) -> f64 {
+    let d_lat = (lat2 - lat1).to_radians();
+    let d_lng = (lng2 - lng1).to_radians();
+    let a = (d_lat / 2.0).sin().powi(2) + lat1.to_radians().cos() * lat2.to_radians().cos() * (d_lng / 2.0).sin().powi(2);
+    2.0 * EARTH_RADIUS_M * a.sqrt().asin()
+}
+
+enum ReadError {
+    Io(std::io::Error),
+    Xml(roxmltree::Error),
+    MissingTrack,
+    MissingType,
+    BadCoordinate(String),
+    Fit(String),
+    Json(serde_json::Error),
+    Zip(zip::result::ZipError),
+}
+
+impl fmt::Display for ReadError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ReadError::Io(e) => write!(f, "IO error: {}", e),
+            ReadError::Xml(e) => write!(f, "invalid XML: {}", e),
+            ReadError::MissingTrack => write!(f, "no track, route or waypoint found"),
+            ReadError::MissingType => write!(f, "track has no type"),
+            ReadError::BadCoordinate(value) => write!(f, "bad coordinate: {}", value),
+            ReadError::Fit(message) => write!(f, "invalid FIT file: {}", message),
+            ReadError::Json(e) => write!(f, "invalid JSON: {}", e),
+            ReadError::Zip(e) => write!(f, "invalid zip archive: {}", e),
+        }
+    }
+}
+
+impl From<std::io::Error> for ReadError {
+    fn from(e: std::io::Error) -> Self {
+        ReadError::Io(e)
+    }
+}
+
+impl From<serde_json::Error> for ReadError {
+    fn from(e: serde_json::Error) -> Self {
+        ReadError::Json(e)
+    }
+}
+
+impl From<zip::result::ZipError> for ReadError {
+    fn from(e: zip::result::ZipError) -> Self {
+        ReadError::Zip(e)
+    }
+}
+
+impl From<roxmltree::Error> for ReadError {
+    fn from(e: roxmltree::Error) -> Self {
+        ReadError::Xml(e)
+    }
+}
+
+// Everything read from one input file
+type ParseResult = Result<(Vec<CoordsFile>, Option<WaypointFile>), ReadError>;
+
+struct ParsedInput {
+    parsed_files: Vec<CoordsFile>,
+    waypoint_files: Vec<WaypointFile>,
+    // skipped files and why, reported at the end of the run
+    failed_files: Vec<(String, ReadError)>,
+}
+
+const INPUT_EXTENSIONS: [&str; 5] = [".gpx", ".tcx", ".fit", ".geojson", ".kml"];
+
+// Category used for planned routes (<rte>), written to coords_route
+const ROUTE_TYPE: &str = "route";
+
+struct Polygon {
+    exterior: Vec<LatLng>,
+    holes: Vec<Vec<LatLng>>,
+    // bounding box of the exterior ring, to skip the ring test for far away points
+    min: LatLng,
+    max: LatLng,
+}
+
+impl Polygon {
+    fn new(exterior: Vec<LatLng>, holes: Vec<Vec<LatLng>>) -> Polygon {
+        let mut min = LatLng { lat: f64::MAX, lng: f64::MAX };
+        let mut max = LatLng { lat: f64::MIN, lng: f64::MIN };
+        for point in &exterior {
+            min.lat = min.lat.min(point.lat);
+            min.lng = min.lng.min(point.lng);
+            max.lat = max.lat.max(point.lat);
+            max.lng = max.lng.max(point.lng);
+        }
+        Polygon { exterior, holes, min, max }
+    }
+}
+
+enum Region {
+    // min, max
+    Rect(LatLng, LatLng),
+    // Polygon / MultiPolygon from a GeoJSON file
+    Polygons(Vec<Polygon>),
 }
 
 struct CustomGroup {
     name: String,
     trk_type: String,
-    min_latlng: LatLng,
-    max_latlng: LatLng,
+    region: Region,
     min_id: i64,
     max_id: i64,
+    // share of the track (points or length) which has to be inside the region
+    min_fraction: f64,
+    measure: config::GroupMeasure,
+    // start time criteria, checked in the group's time zone
+    start_date: Option<NaiveDate>,
+    // exclusive
+    end_date: Option<NaiveDate>,
+    weekdays: Vec<Weekday>,
+    // start hour inclusive, end hour exclusive, wraps around midnight if start > end
+    hours: Option<(u32, u32)>,
+    time_zone: config::GroupTimeZone,
+    priority: i64,
 }
 
 struct Options {
     verbose: bool,
     output_path_str: String,
-    gpx_path_str: String,
+    gpx_paths: Vec<String>,
+    recursive: bool,
+    follow_symlinks: bool,
+    include_strs: Vec<String>,
+    exclude_strs: Vec<String>,
+    include_globs: GlobSet,
+    exclude_globs: GlobSet,
     ignore_file_str: String,
     html_output_str: String,
     html_output: bool,
     custom_group_str: String,
-    custom_groups: Vec<CustomGroup>
+    custom_groups: Vec<CustomGroup>,
+    fields_str: String,
+    output_fields: Vec<PointField>,
+    strict: bool,
+    jobs: usize,
+    config_str: String,
+    config: config::Config,
+    type_property_str: String,
+    precision: u32,
+    prefer_str: String,
+    prefer_newest: bool,
 }
 
 struct OverLap {
@@ -59,29 +369,75 @@ struct OverLap {
     not_overlap: HashSet<String>
 }
 
+impl Default for Options {
+    fn default() -> Self {
+        Options {
+            verbose: false,
+            output_path_str: "".to_string(),
+            gpx_paths: vec![],
+            recursive: false,
+            follow_symlinks: false,
+            include_strs: vec![],
+            exclude_strs: vec![],
+            include_globs: GlobSet::empty(),
+            exclude_globs: GlobSet::empty(),
+            ignore_file_str: "".to_string(),
+            html_output_str: "".to_string(),
+            html_output: false,
+            custom_group_str: "".to_string(),
+            custom_groups: vec![],
+            fields_str: "lat,lng".to_string(),
+            output_fields: vec![],
+            strict: false,
+            jobs: 0,
+            config_str: "".to_string(),
+            config: config::Config::default(),
+            type_property_str: "type".to_string(),
+            precision: 6,
+            prefer_str: "oldest".to_string(),
+            prefer_newest: false,
+        }
+    }
+}
+
 fn parse_args() -> Options {
-    let mut options = Options {
-        verbose: false,
-        output_path_str: "".to_string(),
-        gpx_path_str: "".to_string(),
-        ignore_file_str: "".to_string(),
-        html_output_str: "".to_string(),
-        html_output: false,
-        custom_group_str: "".to_string(),
-        custom_groups: vec![],
-    };
+    let mut options = Options::default();
 
     {
         let mut ap = ArgumentParser::new();
         ap.refer(&mut options.verbose)
             .add_option(&["-v", "--verbose"], StoreTrue, "Be verbose");
-        ap.refer(&mut options.gpx_path_str)
+        ap.refer(&mut options.gpx_paths)
             .add_option(
                 &["-i", "--input-directory"],
-                Store,
-                "Input directory containing *.gpx files",
+                Collect,
+                "Input directory containing *.gpx, *.tcx, *.fit, *.geojson and *.kml files (optionally *.gz), or *.zip/*.tar(.gz) archives, can be given multiple times",
             )
             .required();
+        ap.refer(&mut options.recursive)
+            .add_option(
+                &["-r", "--recursive"],
+                StoreTrue,
+                "Descend into subdirectories of the input directories",
+            );
+        ap.refer(&mut options.follow_symlinks)
+            .add_option(
+                &["--follow-symlinks"],
+                StoreTrue,
+                "Follow symbolic links while reading the input directories",
+            );
+        ap.refer(&mut options.include_strs)
+            .add_option(
+                &["--include"],
+                Collect,
+                "Only read files matching this glob, relative to the input directory (e.g. '2023/**'), can be given multiple times",
+            );
+        ap.refer(&mut options.exclude_strs)
+            .add_option(
+                &["--exclude"],
+                Collect,
+                "Do not read files matching this glob, relative to the input directory, can be given multiple times",
+            );
         ap.refer(&mut options.output_path_str)
             .add_option(
                 &["-o", "--output-directory"],
@@ -102,49 +458,126 @@ fn parse_args() -> Options {
                 Store,
                 "Path to an extra HTML (part) output",
             );
+        ap.refer(&mut options.config_str)
+            .add_option(
+                &["--config"],
+                Store,
+                "Path to a TOML config file (activity type aliases, ...)",
+            );
         ap.refer(&mut options.custom_group_str)
             .add_option(
                 &["-c", "--custom-groups"],
                 Store,
-                "Path to a file with custom groups",
+                "Path to a file with custom groups (*.toml, or the ; separated format)",
+            );
+        ap.refer(&mut options.type_property_str)
+            .add_option(
+                &["--type-property"],
+                Store,
+                "GeoJSON property / KML ExtendedData name holding the activity type (default: type)",
+            );
+        ap.refer(&mut options.strict)
+            .add_option(
+                &["--strict"],
+                StoreTrue,
+                "Exit with a non-zero code if any input file could not be read",
+            );
+        ap.refer(&mut options.jobs)
+            .add_option(
+                &["-j", "--jobs"],
+                Store,
+                "Number of worker threads for parsing and processing (default: 0 = number of CPUs)",
+            );
+        ap.refer(&mut options.fields_str)
+            .add_option(
+                &["-f", "--fields"],
+                Store,
+                "Comma separated point attributes to output: lat,lng,ele,t,hr,cad,atemp (default: lat,lng)",
+            );
+        ap.refer(&mut options.precision)
+            .add_option(
+                &["--precision"],
+                Store,
+                "Decimals of the output coordinates, 5 is about 1 m (default: 6)",
+            );
+        ap.refer(&mut options.prefer_str)
+            .add_option(
+                &["--prefer"],
+                Store,
+                "Which version of a repeated route is kept: oldest or newest (default: oldest)",
             );
         ap.parse_args_or_exit();
     }
     options.html_output = !options.html_output_str.is_empty();
+    if !options.config_str.is_empty() {
+        options.config = match config::load_config(&options.config_str) {
+            Ok(config) => config,
+            Err(e) => {
+                eprintln!("Invalid config file {}: {}", options.config_str, e);
+                std::process::exit(2);
+            }
+        };
+    }
+    options.include_globs = build_glob_set(&options.include_strs);
+    options.exclude_globs = build_glob_set(&options.exclude_strs);
+    options.output_fields = match parse_point_fields(&options.fields_str) {
+        Some(fields) => fields,
+        None => {
+            eprintln!("Invalid --fields value: {}", options.fields_str);
+            std::process::exit(2);
+        }
+    };
+    if options.precision > MAX_PRECISION {
+        eprintln!("Invalid --precision value: {}, at most {}", options.precision, MAX_PRECISION);
+        std::process::exit(2);
+    }
+    options.prefer_newest = match options.prefer_str.as_str() {
+        "oldest" => false,
+        "newest" => true,
+        _ => {
+            eprintln!("Invalid --prefer value: {}", options.prefer_str);
+            std::process::exit(2);
+        }
+    };
 
     options
 }
 
-fn read_custom_groups(options: & mut Options) {
-    let file = File::open(&options.custom_group_str).expect("no such file");
-    let buf = BufReader::new(file);
-    for line in buf.lines() {
-        let full_line = line.unwrap();
-        println!("line: {}", full_line);
-        let mut parts = full_line.split(";");
-        if parts.clone().count() != 8 {
-            println!("Invalid line: {}", full_line);
-            continue;
+fn build_glob_set(patterns: &Vec<String>) -> GlobSet {
+    let mut builder = GlobSetBuilder::new();
+    for pattern in patterns {
+        match Glob::new(pattern) {
+            Ok(glob) => {
+                builder.add(glob);
+            }
+            Err(e) => {
+                eprintln!("Invalid glob pattern {}: {}", pattern, e);
+                std::process::exit(2);
+            }
         }
+    }
+    builder.build().unwrap()
+}
 
-        let mut custom_group : CustomGroup = CustomGroup {
-            name: "".to_string(),
-            trk_type: "".to_string(),
-            min_latlng: LatLng { lat: 0.0, lng: 0.0 },
-            max_latlng: LatLng { lat: 0.0, lng: 0.0 },
-            min_id: 0,
-            max_id: 0,
-        };
-        custom_group.name = parts.next().unwrap().to_string();
-        custom_group.trk_type = parts.next().unwrap().to_string();
-        custom_group.min_latlng.lat = parts.next().unwrap().to_string().parse::<f64>().unwrap();
-        custom_group.min_latlng.lng = parts.next().unwrap().to_string().parse::<f64>().unwrap();
-        custom_group.max_latlng.lat = parts.next().unwrap().to_string().parse::<f64>().unwrap();
-        custom_group.max_latlng.lng = parts.next().unwrap().to_string().parse::<f64>().unwrap();
-        custom_group.min_id = parts.next().unwrap().to_string().parse::<i64>().unwrap();
-        custom_group.max_id = parts.last().unwrap().to_string().parse::<i64>().unwrap();
+fn read_region_file(path: &Path) -> Result<Region, String> {
+    let text = fs::read_to_string(path).map_err(|e| e.to_string())?;
+    let polygons = geojson::parse_polygons(&text).map_err(|e| e.to_string())?;
+    if polygons.is_empty() {
+        return Err("no Polygon or MultiPolygon found".to_string());
+    }
+    Ok(Region::Polygons(polygons))
+}
 
-        options.custom_groups.push(custom_group);
+fn read_custom_groups(options: & mut Options) {
+    match groups::read_groups_file(&options.custom_group_str, options) {
+        Ok(custom_groups) => options.custom_groups = custom_groups,
+        Err(errors) => {
+            for error in errors {
+                eprintln!("{}", error);
+            }
+            eprintln!("Invalid custom groups file: {}", options.custom_group_str);
+            std::process::exit(2);
+        }
     }
 }
 
@@ -159,16 +592,115 @@ fn point_in_bounds(point: &LatLng, min: &LatLng, max: &LatLng) -> bool {
     false
 }
 
-fn point_in_custom_group(coords_file: &CoordsFile, custom_group : &CustomGroup) -> bool {
+// Ray casting (even-odd rule), the ring may or may not repeat its first point
+fn point_in_ring(point: &LatLng, ring: &[LatLng]) -> bool {
+    let mut inside = false;
+    let mut j = ring.len().wrapping_sub(1);
+    for i in 0..ring.len() {
+        let (a, b) = (&ring[i], &ring[j]);
+        if (a.lat > point.lat) != (b.lat > point.lat)
+            && point.lng < (b.lng - a.lng) * (point.lat - a.lat) / (b.lat - a.lat) + a.lng
+        {
+            inside = !inside;
+        }
+        j = i;
+    }
+    inside
+}
 
-    if point_in_bounds(&coords_file.min, &custom_group.min_latlng, &custom_group.max_latlng) {
-        return true;
+fn point_in_polygon(point: &LatLng, polygon: &Polygon) -> bool {
+    if !point_in_bounds(point, &polygon.min, &polygon.max) {
+        return false;
+    }
+    point_in_ring(point, &polygon.exterior) && !polygon.holes.iter().any(|hole| point_in_ring(point, hole))
+}
+
+fn point_in_region(point: &LatLng, region: &Region) -> bool {
+    match region {
+        Region::Rect(min, max) => point_in_bounds(point, min, max),
+        Region::Polygons(polygons) => polygons.iter().any(|polygon| point_in_polygon(point, polygon)),
     }
-    if point_in_bounds(&coords_file.max, &custom_group.min_latlng, &custom_group.max_latlng) {
+}
+
+// Share of the track inside the region, by point count or by length
+// (a piece between two points counts as inside if its midpoint is)
+fn fraction_in_region(coords_file: &CoordsFile, region: &Region, measure: config::GroupMeasure) -> f64 {
+    let mut inside = 0.0;
+    let mut total = 0.0;
+    match measure {
+        config::GroupMeasure::Points => {
+            for point in coords_file.points() {
+                total += 1.0;
+                if point_in_region(&point.latlng(), region) {
+                    inside += 1.0;
+                }
+            }
+        }
+        config::GroupMeasure::Length => {
+            for segment in &coords_file.segments {
+                for pair in segment.windows(2) {
+                    let length = pair[0].distance_m(&pair[1]);
+                    let midpoint = LatLng {
+                        lat: (pair[0].lat + pair[1].lat) / 2.0,
+                        lng: (pair[0].lng + pair[1].lng) / 2.0,
+                    };
+                    total += length;
+                    if point_in_region(&midpoint, region) {
+                        inside += length;
+                    }
+                }
+            }
+        }
+    }
+
+    if total == 0.0 {
+        return 0.0;
+    }
+    inside / total
+}
+
+fn time_in_custom_group(coords_file: &CoordsFile, custom_group: &CustomGroup) -> bool {
+    if custom_group.start_date.is_none()
+        && custom_group.end_date.is_none()
+        && custom_group.weekdays.is_empty()
+        && custom_group.hours.is_none()
+    {
         return true;
     }
 
-    false
+    let start_time = match coords_file.start_time() {
+        Some(start_time) => custom_group.time_zone.local_time(start_time),
+        None => return false,
+    };
+    let date = start_time.date();
+
+    if custom_group.start_date.is_some_and(|start_date| date < start_date) {
+        return false;
+    }
+    if custom_group.end_date.is_some_and(|end_date| date >= end_date) {
+        return false;
+    }
+    if !custom_group.weekdays.is_empty() && !custom_group.weekdays.contains(&start_time.weekday()) {
+        return false;
+    }
+    if let Some((start_hour, end_hour)) = custom_group.hours {
+        let hour = start_time.hour();
+        let in_window = if start_hour <= end_hour {
+            hour >= start_hour && hour < end_hour
+        } else {
+            hour >= start_hour || hour < end_hour
+        };
+        if !in_window {
+            return false;
+        }
+    }
+
+    true
+}
+
+fn point_in_custom_group(coords_file: &CoordsFile, custom_group : &CustomGroup) -> bool {
+    let fraction = fraction_in_region(coords_file, &custom_group.region, custom_group.measure);
+    fraction > 0.0 && fraction >= custom_group.min_fraction
 }
 
 fn read_skip_list(options: &Options) -> HashSet<String> {
@@ -189,244 +721,691 @@ fn read_skip_list(options: &Options) -> HashSet<String> {
     skip_list
 }
 
-fn extract_number_from_filename(_filename: &str) -> Option<i64> {
+fn extract_id_from_filename(fullpath: &str, options: &Options) -> Option<i64> {
+    let filename = Path::new(fullpath).file_name()?.to_str()?;
+    options.config.ids.extract_id(filename)
+}
+
+// Trailing number of a link like https://www.strava.com/activities/123
+fn extract_id_from_link(href: &str) -> Option<i64> {
+    href.trim_end_matches('/').rsplit('/').next()?.parse::<i64>().ok()
+}
+
+fn child_text(node: roxmltree::Node, tag: &str) -> Option<String> {
+    node.children()
+        .find(|n| n.has_tag_name(tag))
+        .and_then(|n| n.text())
+        .map(|text| text.to_string())
+}
+
+fn parse_time(text: &str) -> Option<DateTime<Utc>> {
+    DateTime::parse_from_rfc3339(text.trim())
+        .ok()
+        .map(|time| time.with_timezone(&Utc))
+}
+
+fn read_lat_lng(node: roxmltree::Node) -> Result<(f64, f64), ReadError> {
+    let parse = |name: &str| {
+        let value = node.attribute(name).unwrap();
+        value
+            .trim()
+            .parse::<f64>()
+            .map_err(|_| ReadError::BadCoordinate(format!("{}=\"{}\"", name, value)))
+    };
+    Ok((parse("lat")?, parse("lon")?))
+}
 
-    let path = Path::new(_filename);
-    let filename = path.file_name().unwrap().to_str().unwrap();
+fn read_segment(parent: roxmltree::Node, point_tag: &str, coord_file: &mut CoordsFile, options: &Options) -> Result<(), ReadError> {
+    let mut segment: Vec<TrackPoint> = Vec::new();
+    for trkpt in parent.children().filter(|n| n.has_tag_name(point_tag)) {
+        if trkpt.has_attribute("lat") && trkpt.has_attribute("lon") {
+            let (lat, lng) = read_lat_lng(trkpt)?;
+
+            if options.verbose {
+                println!("Found point {} {}", lat, lng);
+            }
+
+            let mut point = TrackPoint::new(lat, lng);
+            point.ele = child_text(trkpt, "ele").and_then(|ele| ele.trim().parse::<f64>().ok());
+            point.time = child_text(trkpt, "time").and_then(|time| parse_time(&time));
+            // gpxtpx:TrackPointExtension, matched by local name to accept any namespace version
+            if let Some(extensions) = trkpt.children().find(|n| n.has_tag_name("extensions")) {
+                for value in extensions.descendants().filter(|n| n.is_element()) {
+                    let text = match value.text() {
+                        Some(text) => text.trim(),
+                        None => continue,
+                    };
+                    match value.tag_name().name() {
+                        "hr" => point.hr = text.parse::<u32>().ok(),
+                        "cad" => point.cad = text.parse::<u32>().ok(),
+                        "atemp" => point.atemp = text.parse::<f64>().ok(),
+                        _ => {}
+                    }
+                }
+            }
+            segment.push(point);
+        }
+    }
+
+    coord_file.add_segment(segment);
+
+    Ok(())
+}
+
+fn matches_custom_group(coord_file: &CoordsFile, custom_group: &CustomGroup) -> bool {
+    if !custom_group.trk_type.is_empty() && coord_file.trk_type != custom_group.trk_type {
+        return false;
+    }
 
-    let parts: Vec<&str> = filename.split(&['_', '.'][..]).collect();
+    if !point_in_custom_group(coord_file, custom_group) {
+        return false;
+    }
 
-    if parts.len() >= 2 {
-        if let Ok(number) = parts[1].parse::<i64>() {
-            return Some(number);
+    if custom_group.min_id != -1 && custom_group.max_id != -1 {
+        match coord_file.id {
+            Some(number) if number >= custom_group.min_id && number <= custom_group.max_id => {}
+            _ => return false,
         }
     }
 
-    None
+    time_in_custom_group(coord_file, custom_group)
 }
 
-fn read_files(options: &Options) -> Vec<CoordsFile> {
-    let input_path = Path::new(&options.gpx_path_str);
+// One file per group tag, or the file unchanged if no group matches
+fn apply_custom_groups(coord_file: CoordsFile, options: &Options) -> Vec<CoordsFile> {
+    let matching: Vec<&CustomGroup> = options
+        .custom_groups
+        .iter()
+        .filter(|custom_group| matches_custom_group(&coord_file, custom_group))
+        .collect();
+
+    let mut tags: Vec<&str> = Vec::new();
+    match options.config.groups.mode {
+        // max_by_key returns the last of equal priorities
+        config::GroupMode::Priority => {
+            if let Some(custom_group) = matching.iter().max_by_key(|custom_group| custom_group.priority) {
+                tags.push(&custom_group.name);
+            }
+        }
+        config::GroupMode::Tags => {
+            for custom_group in &matching {
+                if !tags.contains(&custom_group.name.as_str()) {
+                    tags.push(&custom_group.name);
+                }
+            }
+        }
+    }
+    if tags.is_empty() {
+        return vec![coord_file];
+    }
 
-    let paths = fs::read_dir(input_path).unwrap();
+    if options.verbose {
+        println!("Overwriting trk type for file: {} from {} to {}", coord_file.name, coord_file.trk_type, tags.join(", "));
+    }
+    tags.iter()
+        .map(|tag| {
+            let mut tagged = coord_file.clone();
+            tagged.trk_type = tag.to_string();
+            tagged.multi_group = tags.len() > 1;
+            tagged
+        })
+        .collect()
+}
+
+fn parse_gpx(fullpath: &str, text: &str, options: &Options) -> ParseResult {
+    let opt = roxmltree::ParsingOptions {
+        allow_dtd: true,
+        ..roxmltree::ParsingOptions::default()
+    };
+    let doc = roxmltree::Document::parse_with_options(text, opt)?;
 
     let mut parsed_files: Vec<CoordsFile> = Vec::new();
 
-    let skip_set = read_skip_list(&options);
+    // Recorded tracks
+    let mut trk_nodes = doc.descendants().filter(|n| n.has_tag_name("trk")).peekable();
+    if let Some(first_trk) = trk_nodes.peek() {
+        // a missing type stays empty and is handled by categorize
+        let trk_type = child_text(*first_trk, "type").unwrap_or_default();
+        let mut coord_file = CoordsFile::new(fullpath, trk_type.trim());
+        let metadata = doc.descendants().find(|n| n.has_tag_name("metadata"));
+        coord_file.metadata_time = metadata
+            .and_then(|metadata| child_text(metadata, "time"))
+            .and_then(|time| parse_time(&time));
+        coord_file.id = metadata
+            .into_iter()
+            .chain(std::iter::once(*first_trk))
+            .flat_map(|node| node.children().filter(|n| n.has_tag_name("link")))
+            .find_map(|link| link.attribute("href").and_then(extract_id_from_link));
+        if options.verbose {
+            println!("Found trk type {}", coord_file.trk_type);
+        }
+
+        for trk_node in trk_nodes {
+            for trk_seg in trk_node.children().filter(|n| n.has_tag_name("trkseg")) {
+                read_segment(trk_seg, "trkpt", &mut coord_file, options)?;
+            }
+        }
+
+        // a trk without points is not a track, without routes and waypoints the file fails below
+        if !coord_file.segments.is_empty() {
+            parsed_files.push(coord_file);
+        }
+    }
 
-    for path in paths {
-        let dir_entry = path.unwrap();
-        let fullpath = dir_entry.path().display().to_string();
+    // Planned routes, every rte is one segment
+    let rte_nodes: Vec<roxmltree::Node> = doc.descendants().filter(|n| n.has_tag_name("rte")).collect();
+    if !rte_nodes.is_empty() {
+        let mut route_file = CoordsFile::new(fullpath, ROUTE_TYPE);
+        for rte_node in rte_nodes {
+            read_segment(rte_node, "rtept", &mut route_file, options)?;
+        }
         if options.verbose {
-            println!("Reading: {}", fullpath);
+            println!("Found {} route(s)", route_file.segments.len());
         }
 
-        if !fullpath.ends_with(".gpx") {
-            if options.verbose {
-                println!("Skipping: {}", fullpath);
+        if !route_file.segments.is_empty() {
+            parsed_files.push(route_file);
+        }
+    }
+
+    // Waypoints / POIs
+    let mut waypoints: Vec<Waypoint> = Vec::new();
+    for wpt in doc.descendants().filter(|n| n.has_tag_name("wpt")) {
+        if wpt.has_attribute("lat") && wpt.has_attribute("lon") {
+            let (lat, lng) = read_lat_lng(wpt)?;
+
+            waypoints.push(Waypoint {
+                point: LatLng { lat, lng },
+                name: child_text(wpt, "name"),
+                desc: child_text(wpt, "desc"),
+                sym: child_text(wpt, "sym"),
+            });
+        }
+    }
+
+    if parsed_files.is_empty() && waypoints.is_empty() {
+        return Err(ReadError::MissingTrack);
+    }
+
+    let waypoint_file = if waypoints.is_empty() {
+        None
+    } else {
+        if options.verbose {
+            println!("Found {} waypoint(s)", waypoints.len());
+        }
+        Some(WaypointFile { name: fullpath.to_string(), waypoints, output_name: String::new() })
+    };
+
+    Ok((parsed_files, waypoint_file))
+}
+
+// Plain or gzip compressed (*.gpx.gz, ...) input file
+fn is_supported(filename: &str) -> bool {
+    let filename = filename.strip_suffix(".gz").unwrap_or(filename);
+    INPUT_EXTENSIONS.iter().any(|extension| filename.ends_with(extension))
+}
+
+// The skip list contains file names, archive members match with their full path or file name
+fn is_skipped(skip_set: &HashSet<String>, name: &str) -> bool {
+    let filename = Path::new(name).file_name().map(|filename| filename.to_string_lossy());
+    skip_set.contains(name) || filename.is_some_and(|filename| skip_set.contains(filename.as_ref()))
+}
+
+// Fill in (or correct) the activity type from the moving speed, see [infer] in the config
+fn infer_activity_type(coord_file: &mut CoordsFile, options: &Options) -> Result<(), ReadError> {
+    let infer_config = &options.config.infer;
+    let missing = coord_file.trk_type.is_empty();
+    if infer_config.mode == config::InferMode::Off || (!missing && infer_config.mode == config::InferMode::Missing) {
+        return if missing { Err(ReadError::MissingType) } else { Ok(()) };
+    }
+
+    let (inferred, speed) = match infer::infer_type(coord_file, infer_config) {
+        Some(inferred) => inferred,
+        // no timestamps, nothing to infer from
+        None => return if missing { Err(ReadError::MissingType) } else { Ok(()) },
+    };
+
+    if missing {
+        if options.verbose {
+            println!("Inferred type {} for {}, moving speed {:.1} km/h", inferred, coord_file.name, speed);
+        }
+        coord_file.trk_type = inferred.to_string();
+        return Ok(());
+    }
+
+    // only a category the speed can tell apart is overridden, e.g. not swimming or an unmapped type
+    let types = &options.config.types;
+    let declared = types.map_type(&coord_file.trk_type);
+    let inferable = declared
+        .as_ref()
+        .is_some_and(|declared| infer::INFERRED_TYPES.iter().any(|inferred| types.map_type(inferred).as_ref() == Some(declared)));
+    if inferable && declared != types.map_type(inferred) {
+        println!(
+            "Warning: {} is declared as {}, but moving speed {:.1} km/h suggests {}, using {}",
+            coord_file.name, coord_file.trk_type, speed, inferred, inferred
+        );
+        coord_file.trk_type = inferred.to_string();
+    }
+    Ok(())
+}
+
+// Map the raw activity type to its category and apply the custom groups,
+// files with an unmapped type are dropped when configured so
+fn categorize(coord_files: Vec<CoordsFile>, options: &Options) -> Result<Vec<CoordsFile>, ReadError> {
+    let mut categorized = Vec::new();
+    for mut coord_file in coord_files {
+        if let Some(id) = extract_id_from_filename(&coord_file.name, options) {
+            coord_file.id = Some(id);
+        }
+
+        if coord_file.trk_type != ROUTE_TYPE {
+            infer_activity_type(&mut coord_file, options)?;
+
+            match options.config.types.map_type(&coord_file.trk_type) {
+                Some(category) => coord_file.trk_type = category,
+                None => {
+                    if options.verbose {
+                        println!("Dropping {}, unmapped type: {}", coord_file.name, coord_file.trk_type);
+                    }
+                    continue;
+                }
             }
+            categorized.extend(apply_custom_groups(coord_file, options));
             continue;
         }
+        categorized.push(coord_file);
+    }
+    Ok(categorized)
+}
 
-        let file_name = dir_entry.file_name();
-        let filename= file_name.to_str().unwrap();
-        if skip_set.contains(filename) {
-            if options.verbose {
-                println!("Skipping: {}, in skip list", fullpath);
+fn parse_input(fullpath: &str, data: &[u8], options: &Options) -> ParseResult {
+    let (coord_files, waypoint_file) = parse_format(fullpath, data, options)?;
+    Ok((categorize(coord_files, options)?, waypoint_file))
+}
+
+// Dispatch on the file extension, fullpath has to pass is_supported
+fn parse_format(fullpath: &str, data: &[u8], options: &Options) -> ParseResult {
+    let mut decompressed = Vec::new();
+    let (format_path, data) = match fullpath.strip_suffix(".gz") {
+        Some(format_path) => {
+            GzDecoder::new(data).read_to_end(&mut decompressed)?;
+            (format_path, decompressed.as_slice())
+        }
+        None => (fullpath, data),
+    };
+
+    if format_path.ends_with(".fit") {
+        return fit::parse_fit(fullpath, data, options).map(|coord_files| (coord_files, None));
+    }
+
+    let text = std::str::from_utf8(data)
+        .map_err(|e| ReadError::Io(std::io::Error::new(std::io::ErrorKind::InvalidData, e)))?;
+    let coord_files = if format_path.ends_with(".tcx") {
+        tcx::parse_tcx(fullpath, text, options)?
+    } else if format_path.ends_with(".geojson") {
+        geojson::parse_geojson(fullpath, text, options)?
+    } else if format_path.ends_with(".kml") {
+        kml::parse_kml(fullpath, text, options)?
+    } else {
+        return parse_gpx(fullpath, text, options);
+    };
+    Ok((coord_files, None))
+}
+
+fn add_parse_result(parsed_input: &mut ParsedInput, fullpath: String, relative_path: &str, result: ParseResult) {
+    match result {
+        Ok((mut coord_files, mut waypoint_file)) => {
+            let output_name = output_base_name(relative_path);
+            for coord_file in &mut coord_files {
+                coord_file.output_name = output_name.clone();
             }
-            continue;
+            if let Some(waypoint_file) = &mut waypoint_file {
+                waypoint_file.output_name = output_name;
+            }
+            parsed_input.parsed_files.extend(coord_files);
+            parsed_input.waypoint_files.extend(waypoint_file);
+        }
+        Err(e) => {
+            println!("Skipping {}: {}", fullpath, e);
+            parsed_input.failed_files.push((fullpath, e));
         }
+    }
+}
 
-        let mut coord_file = CoordsFile {
-            name: fullpath.clone(),
-            trk_type: "".to_string(),
-            coords: vec![],
-            min: LatLng { lat: f64::MAX, lng: f64::MAX },
-            max: LatLng { lat: 0.0, lng: 0.0 },
-        };
+// --include/--exclude, matched against the path relative to the input directory
+fn is_selected(relative_path: &str, options: &Options) -> bool {
+    if options.exclude_globs.is_match(relative_path) {
+        return false;
+    }
+    options.include_globs.is_empty() || options.include_globs.is_match(relative_path)
+}
 
-        let text = std::fs::read_to_string(fullpath).unwrap();
-        let opt = roxmltree::ParsingOptions {
-            allow_dtd: true,
-            ..roxmltree::ParsingOptions::default()
-        };
-        let doc = match roxmltree::Document::parse_with_options(&text, opt) {
-            Ok(v) => v,
-            Err(e) => {
-                println!("Skipping invalid file: {}", e);
-                continue;
+// full path and path relative to the input directory
+enum InputFile {
+    Plain(String, String),
+    Archive(String, String),
+}
+
+// full path, path relative to the input directory (or inside the archive) and what was read
+type InputResult = (String, String, ParseResult);
+
+// Archive members are parsed in batches while the archive is read, a yearly tarball is never decompressed
+// into memory as a whole. A batch is parsed when it reaches either limit.
+const ARCHIVE_BATCH_MEMBERS_PER_THREAD: usize = 4;
+const ARCHIVE_BATCH_BYTES: usize = 64 * 1024 * 1024;
+
+// archive member: full path, relative path of the archive without extension plus the path inside it, decompressed data
+type ArchiveMember = (String, String, Vec<u8>);
+
+fn parse_archive_batch(batch: &mut Vec<ArchiveMember>, results: &mut Vec<InputResult>, options: &Options) {
+    let parsed: Vec<InputResult> = std::mem::take(batch)
+        .into_par_iter()
+        .map(|(member_path, member_name, data)| {
+            let result = parse_input(&member_path, &data, options);
+            (member_path, member_name, result)
+        })
+        .collect();
+    results.extend(parsed);
+}
+
+fn read_archive(
+    fullpath: &str,
+    relative_path: &str,
+    skip_set: &HashSet<String>,
+    options: &Options,
+) -> Vec<InputResult> {
+    // Members are read in archive order, every batch is parsed in parallel
+    let batch_members = ARCHIVE_BATCH_MEMBERS_PER_THREAD * rayon::current_num_threads();
+    let mut results: Vec<InputResult> = Vec::new();
+    let mut batch: Vec<ArchiveMember> = Vec::new();
+    let mut batch_bytes = 0;
+    // 2021.tar.gz/01/track.gpx is written as 2021_01_track, members of yearly archives don't clash
+    let archive_name = archive::strip_extension(relative_path);
+    let result = archive::for_each_member(fullpath, |member_name, data| {
+        let member_path = format!("{}/{}", fullpath, member_name);
+        if options.verbose {
+            println!("Reading: {}", member_path);
+        }
+
+        if !is_supported(member_name) {
+            if options.verbose {
+                println!("Skipping: {}", member_path);
             }
-        };
+            return;
+        }
+        if !is_selected(&format!("{}/{}", relative_path, member_name), options) {
+            if options.verbose {
+                println!("Skipping: {}, not selected by --include/--exclude", member_path);
+            }
+            return;
+        }
+        if is_skipped(skip_set, member_name) {
+            if options.verbose {
+                println!("Skipping: {}, in skip list", member_path);
+            }
+            return;
+        }
 
-        let trk_node = doc.descendants().find(|n| n.has_tag_name("trk")).unwrap();
-        let trk_type = trk_node
-            .children()
-            .find(|n| n.has_tag_name("type"))
-            .unwrap();
-        coord_file.trk_type = trk_type.text().unwrap().to_string();
-        // treat hiking and walking as the same
-        if coord_file.trk_type == "hiking" {
-            coord_file.trk_type = "walking".to_string();
+        batch_bytes += data.len();
+        batch.push((member_path, format!("{}/{}", archive_name, member_name), data));
+        if batch.len() >= batch_members || batch_bytes >= ARCHIVE_BATCH_BYTES {
+            parse_archive_batch(&mut batch, &mut results, options);
+            batch_bytes = 0;
         }
-        if options.verbose {
-            println!("Found trk type {}", coord_file.trk_type);
+    });
+    parse_archive_batch(&mut batch, &mut results, options);
+
+    // the members before a broken part of the archive are kept
+    if let Err(e) = result {
+        results.push((fullpath.to_string(), relative_path.to_string(), Err(e)));
+    }
+    results
+}
+
+fn read_input_file(input_file: &InputFile, skip_set: &HashSet<String>, options: &Options) -> Vec<InputResult> {
+    match input_file {
+        InputFile::Plain(fullpath, relative_path) => {
+            let result = fs::read(fullpath)
+                .map_err(ReadError::from)
+                .and_then(|data| parse_input(fullpath, &data, options));
+            vec![(fullpath.clone(), relative_path.clone(), result)]
         }
+        InputFile::Archive(fullpath, relative_path) => read_archive(fullpath, relative_path, skip_set, options),
+    }
+}
 
-        let trk_seg = trk_node
-            .children()
-            .find(|n| n.has_tag_name("trkseg"))
-            .unwrap();
-        for trkpt in trk_seg.children() {
-            if trkpt.has_attribute("lat") && trkpt.has_attribute("lon") {
-                let lat = trkpt.attribute("lat").unwrap().parse::<f64>().unwrap();
-                let lng = trkpt.attribute("lon").unwrap().parse::<f64>().unwrap();
+fn read_files(options: &Options) -> ParsedInput {
+    let mut parsed_input = ParsedInput {
+        parsed_files: Vec::new(),
+        waypoint_files: Vec::new(),
+        failed_files: Vec::new(),
+    };
 
-                if options.verbose {
-                    println!("Found point {} {}", lat, lng);
-                }
+    let skip_set = read_skip_list(options);
 
-                coord_file.coords.push(LatLng { lat, lng });
+    // Collect the files first, the walk order is sorted and decides the output order
+    let mut input_files: Vec<InputFile> = Vec::new();
+    for input_path_str in &options.gpx_paths {
+        let input_path = Path::new(input_path_str);
+        if !input_path.exists() {
+            eprintln!("Cannot read input directory {}: not found", input_path_str);
+            std::process::exit(1);
+        }
 
-                // Save max/min
-                if lat > coord_file.max.lat {
-                    coord_file.max.lat = lat;
-                }
-                if lng > coord_file.max.lng {
-                    coord_file.max.lng = lng;
-                }
-                if lat < coord_file.min.lat {
-                    coord_file.min.lat = lat;
-                }
-                if lng < coord_file.min.lng {
-                    coord_file.min.lng = lng;
+        let walker = WalkDir::new(input_path)
+            .follow_links(options.follow_symlinks)
+            .max_depth(if options.recursive { usize::MAX } else { 1 })
+            .sort_by_file_name();
+        for path in walker {
+            let dir_entry = match path {
+                Ok(dir_entry) => dir_entry,
+                Err(e) => {
+                    let error_path = e.path().map_or(input_path_str.clone(), |path| path.display().to_string());
+                    parsed_input.failed_files.push((error_path, ReadError::Io(e.into())));
+                    continue;
                 }
+            };
+            if dir_entry.file_type().is_dir() {
+                continue;
+            }
 
+            let fullpath = dir_entry.path().display().to_string();
+            if options.verbose {
+                println!("Reading: {}", fullpath);
             }
-        }
 
-        for custom_group in &options.custom_groups {
-            if custom_group.trk_type.len() != 0 && coord_file.trk_type != custom_group.trk_type {
+            let file_name = dir_entry.file_name();
+            let filename = file_name.to_string_lossy();
+            if !archive::is_archive(&filename) && !is_supported(&filename) {
+                if options.verbose {
+                    println!("Skipping: {}", fullpath);
+                }
                 continue;
             }
 
-            if !point_in_custom_group(&coord_file, custom_group) {
+            // the input path itself can be a single file
+            let relative_path = match dir_entry.path().strip_prefix(input_path) {
+                Ok(relative_path) if dir_entry.depth() > 0 => relative_path.display().to_string(),
+                _ => filename.to_string(),
+            };
+            // archives are not matched by --include, their members are
+            let selected = if archive::is_archive(&filename) {
+                !options.exclude_globs.is_match(&relative_path)
+            } else {
+                is_selected(&relative_path, options)
+            };
+            if !selected {
+                if options.verbose {
+                    println!("Skipping: {}, not selected by --include/--exclude", fullpath);
+                }
                 continue;
             }
 
-            if custom_group.min_id != -1 && custom_group.max_id != -1 {
-                match extract_number_from_filename(&coord_file.name) {
-                    Some(number) => if number >= custom_group.min_id && number <= custom_group.max_id {
-                    } else {
-                        continue
-                    },
-                    None => {
-                        continue
-                    }
+            if skip_set.contains(filename.as_ref()) {
+                if options.verbose {
+                    println!("Skipping: {}, in skip list", fullpath);
                 }
+                continue;
             }
 
-            coord_file.trk_type = custom_group.name.clone();
-            if options.verbose {
-                println!("Overwriting trk type for file: {} to {}", coord_file.name, custom_group.trk_type)
+            if archive::is_archive(&filename) {
+                input_files.push(InputFile::Archive(fullpath, relative_path));
+            } else {
+                input_files.push(InputFile::Plain(fullpath, relative_path));
             }
         }
+    }
 
-        parsed_files.push(coord_file);
+    // Parse on the worker pool, collect keeps the order of input_files
+    let results: Vec<Vec<InputResult>> = input_files
+        .par_iter()
+        .map(|input_file| read_input_file(input_file, &skip_set, options))
+        .collect();
+    for (fullpath, relative_path, result) in results.into_iter().flatten() {
+        add_parse_result(&mut parsed_input, fullpath, &relative_path, result);
     }
 
-    parsed_files
+    parsed_input
 }
 
 fn round_values(parsed_files: &mut Vec<CoordsFile>, options: &Options) {
-    // Round values, example: 51.329793, 6 digits
-    for file in parsed_files {
-        for coord in &mut file.coords {
+    // Round values, example: 51.329793, 6 digits (--precision)
+    // Files are independent, so this and the other per file steps run on the worker pool
+    parsed_files.par_iter_mut().for_each(|file| {
+        for coord in file.segments.iter_mut().flatten() {
             if options.verbose {
                 println!("Before {} {}", coord.lat, coord.lng);
             }
 
-            coord.lat = round_val(coord.lat, 6);
-            coord.lng = round_val(coord.lng, 6);
+            coord.lat = round_val(coord.lat, options.precision);
+            coord.lng = round_val(coord.lng, options.precision);
 
             if options.verbose {
                 println!("After {} {}", coord.lat, coord.lng);
             }
         }
-    }
+    });
 }
 
 fn remove_duplicates(parsed_files: &mut Vec<CoordsFile>, options: &Options) {
     // Remove duplicates
-    for file in parsed_files {
+    parsed_files.par_iter_mut().for_each(|file| {
         if options.verbose {
-            println!("Before dedup {}", file.coords.len());
+            println!("Before dedup {}", file.points().count());
+        }
+        for segment in &mut file.segments {
+            // keeps the first point (and its time/sensor data) of a run at the same position
+            segment.dedup_by(|point, previous| point.same_position(previous));
         }
-        file.coords.dedup();
         if options.verbose {
-            println!("After dedup {}", file.coords.len());
+            println!("After dedup {}", file.points().count());
         }
+    });
+}
+
+// Known values first, in age order
+fn compare_known<T: Ord>(a: Option<T>, b: Option<T>, newest: bool) -> Ordering {
+    match (a, b) {
+        (Some(a), Some(b)) if newest => b.cmp(&a),
+        (Some(a), Some(b)) => a.cmp(&b),
+        (Some(_), None) => Ordering::Less,
+        (None, Some(_)) => Ordering::Greater,
+        (None, None) => Ordering::Equal,
+    }
+}
+
+// The novelty check keeps the first version of a repeated route, so the order decides which one survives.
+// By start time, then activity id, then name.
+fn sort_by_start_time(parsed_files: &mut Vec<CoordsFile>, options: &Options) {
+    // start_time() searches the points without a metadata time, so it is computed once per file
+    let mut timed_files: Vec<(Option<DateTime<Utc>>, CoordsFile)> =
+        parsed_files.drain(..).map(|file| (file.start_time(), file)).collect();
+    timed_files.sort_by(|(a_time, a), (b_time, b)| {
+        compare_known(*a_time, *b_time, options.prefer_newest)
+            .then_with(|| compare_known(a.id, b.id, options.prefer_newest))
+            .then_with(|| compare_known(Some(&a.name), Some(&b.name), options.prefer_newest))
+    });
+    parsed_files.extend(timed_files.into_iter().map(|(_, file)| file));
+}
+
+// Files with the same novelty set are checked against one set of seen points.
+// A shared set and a category can have the same name.
+#[derive(PartialEq)]
+enum NoveltySet {
+    Shared(String),
+    Category(String),
+}
+
+fn novelty_set_of(trk_type: &str, options: &Options) -> NoveltySet {
+    match options.config.novelty.shared_set(trk_type) {
+        Some(name) => NoveltySet::Shared(name.to_string()),
+        None => NoveltySet::Category(trk_type.to_string()),
     }
 }
 
 fn remove_files_without_new_points(parsed_files: &mut Vec<CoordsFile>, options: &Options) {
-    // Check individually:
-    // - hiking and walking
-    // - running
-    // - cycling
-    let mut activity_types: Vec<String> = vec!["walking".to_string(), "running".to_string(), "cycling".to_string()];
-    for custom_group in &options.custom_groups {
-        activity_types.push(custom_group.name.clone());
-    }
-
-    let activity_types_hash: HashSet<String> = activity_types.iter().map(|s| s.to_string()).collect();
-
-    let mut remove_files: Vec<String> = Vec::new();
-
-    for atype in activity_types {
-        // Filter files without any new points
-        let mut map: HashMap<String, HashSet<String>> = HashMap::new();
-        parsed_files.iter_mut().for_each(|file| {
-
-            if file.trk_type == atype {
-                let mut new_points: bool = false;
-                for coord in &file.coords {
-                    let lat = round_val(coord.lat, 4).to_string();
-                    let lng = round_val(coord.lng, 4).to_string();
-
-                    if map.contains_key(&lat) {
-                        let hash_coords = map.get_mut(&lat).unwrap();
-                        if hash_coords.contains(&lng) {
-                            continue;
-                        } else {
-                            hash_coords.insert(lng);
-                            new_points = true;
-                        }
-                    } else {
-                        let mut new_coords = HashSet::new();
-                        new_coords.insert(lng);
-                        map.insert(lat, new_coords);
-                        new_points = true;
-                    }
+    // Check every category individually or in its shared set, unmapped types were already dropped while reading
+    let mut novelty_sets: Vec<NoveltySet> = Vec::new();
+    for file in parsed_files.iter() {
+        let novelty_set = novelty_set_of(&file.trk_type, options);
+        if !novelty_sets.contains(&novelty_set) {
+            novelty_sets.push(novelty_set);
+        }
+    }
+
+    // Indices instead of names, a track and a route can come from the same file
+    let mut remove_files: HashSet<usize> = HashSet::new();
+
+    for novelty_set in novelty_sets {
+        // Filter files without enough new points, a file is compared to the kept files before it only
+        let mut seen = novelty::SeenSet::new(&options.config.novelty);
+        parsed_files.iter_mut().enumerate().for_each(|(index, file)| {
+
+            if novelty_set_of(&file.trk_type, options) == novelty_set {
+                let novelty = seen.novelty(file);
+                let keep = novelty.is_enough(&options.config.novelty);
+                if options.verbose {
+                    println!(
+                        "Novelty of {} ({}): {} new cells, {:.0} m of {:.0} m new ({:.1}%), {}",
+                        file.name,
+                        file.trk_type,
+                        novelty.new_cells,
+                        novelty.new_length_m,
+                        novelty.length_m,
+                        novelty.new_percent(),
+                        if keep { "keeping" } else { "removing" }
+                    );
+                }
+                if !keep {
+                    remove_files.insert(index);
+                    return;
                 }
 
-                if !new_points {
-                    remove_files.push(file.name.clone());
+                let novel_segments = options
+                    .config
+                    .novelty
+                    .clip
+                    .then(|| seen.novel_segments(file, options.config.novelty.clip_margin));
+                file.points().for_each(|coord| seen.insert(coord));
+                if let Some(novel_segments) = novel_segments {
+                    if options.verbose {
+                        println!(
+                            "Clipping {} to {} of {} points",
+                            file.name,
+                            novel_segments.iter().map(|segment| segment.len()).sum::<usize>(),
+                            file.points().count()
+                        );
+                    }
+                    file.replace_segments(novel_segments);
+                    // with clip_margin = 0 single new points are dropped
+                    if file.segments.is_empty() {
+                        remove_files.insert(index);
+                    }
                 }
             }
         });
     }
 
-    // Remove all other
-
-    parsed_files.iter().for_each(|file: &CoordsFile| {
-        if !activity_types_hash.contains(&file.trk_type) {
-            if options.verbose {
-                println!("Found unknown type: {}", file.trk_type);
-            }
-            remove_files.push(file.name.clone());
-        }
-    });
-
     if options.verbose {
         println!(
             "Files to remove: {}, out of {}",
@@ -434,54 +1413,145 @@ fn remove_files_without_new_points(parsed_files: &mut Vec<CoordsFile>, options:
             parsed_files.len()
         );
     }
-    for remove_file in remove_files {
-        let position = parsed_files
-            .iter()
-            .position(|value| *value.name == remove_file)
-            .unwrap();
-        parsed_files.remove(position);
-    }
+    let mut index = 0;
+    parsed_files.retain(|_| {
+        let keep = !remove_files.contains(&index);
+        index += 1;
+        keep
+    });
     if options.verbose {
         println!("Remaining files: {}", parsed_files.len());
     }
 }
 
-fn remove_straight_line_points(parsed_files: &mut Vec<CoordsFile>, options: &Options) {
-    // Remove points on the same line
-    for file in parsed_files {
-        let mut removed_points = 0;
-        let coords = &mut file.coords;
-        let old_coords = coords.len();
-        for i in 0..=coords.len() - 3 {
-            // This can happen because we already removed items
-            if i + 2 >= coords.len() {
-                break;
+fn simplify_tracks(parsed_files: &mut Vec<CoordsFile>, options: &Options) {
+    // (removed points, points before, largest deviation in metres) per file
+    let stats: Vec<(usize, usize, f64)> = parsed_files
+        .par_iter_mut()
+        .map(|file| {
+            let (method, tolerance_m) = options.config.simplify.for_type(&file.trk_type);
+            let old_coords = file.points().count();
+            let mut removed_points = 0;
+            let mut max_deviation_m: f64 = 0.0;
+            for coords in &mut file.segments {
+                let (removed, deviation_m) = simplify::simplify_segment(coords, method, tolerance_m);
+                removed_points += removed;
+                max_deviation_m = max_deviation_m.max(deviation_m);
             }
 
-            if in_line(&coords[i], &coords[i + 2], &coords[i + 1]) {
-                if options.verbose {
-                    println!(
-                        "Removing coord: {} {}, which is between {} {} and {} {}",
-                        coords[i + 1].lat,
-                        coords[i + 1].lng,
-                        coords[i].lat,
-                        coords[i].lng,
-                        coords[i + 2].lat,
-                        coords[i + 2].lng
-                    );
-                }
-                coords.remove(i + 1);
-                removed_points += 1;
+            if options.verbose {
+                println!(
+                    "Removed points: {} out of {}, max deviation {:.2} m, from {}",
+                    removed_points, old_coords, max_deviation_m, file.name
+                );
             }
+            (removed_points, old_coords, max_deviation_m)
+        })
+        .collect();
+
+    println!(
+        "Removed points: {} out of {}, max deviation {:.2} m",
+        stats.iter().map(|stat| stat.0).sum::<usize>(),
+        stats.iter().map(|stat| stat.1).sum::<usize>(),
+        stats.iter().map(|stat| stat.2).fold(0.0, f64::max)
+    );
+}
+
+// Input file name without its extension, used for the output file and variable name
+fn base_name(filename: &str) -> String {
+    let filename = filename.strip_suffix(".gz").unwrap_or(filename);
+    for extension in INPUT_EXTENSIONS {
+        if let Some(stripped) = filename.strip_suffix(extension) {
+            return stripped.to_string();
         }
+    }
+    filename.to_string()
+}
 
-        if options.verbose {
-            println!(
-                "Removed points: {} out of {}, from {}",
-                removed_points, old_coords, file.name
-            );
+// year/2023/Morning_Run.gpx -> year_2023_Morning_Run, files in subdirectories keep their own output
+fn output_base_name(relative_path: &str) -> String {
+    base_name(relative_path).replace(['/', '\\'], "_")
+}
+
+// Reserved words, the strict mode ones, and globals which a var silently doesn't replace
+const JS_RESERVED_WORDS: [&str; 51] = [
+    "await", "break", "case", "catch", "class", "const", "continue", "debugger", "default", "delete", "do", "else",
+    "enum", "export", "extends", "false", "finally", "for", "function", "if", "import", "in", "instanceof", "new",
+    "null", "return", "super", "switch", "this", "throw", "true", "try", "typeof", "var", "void", "while", "with",
+    "yield", "arguments", "eval", "implements", "interface", "let", "package", "private", "protected", "public",
+    "static", "Infinity", "NaN", "undefined",
+];
+
+// Strava names its files 1234567.gpx.gz, Komoot 2023-05-01_123_Tour.gpx, neither is a valid JS variable name
+fn js_identifier(name: &str) -> String {
+    let mut identifier: String = name
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() || c == '_' || c == '$' { c } else { '_' })
+        .collect();
+    if identifier.is_empty()
+        || identifier.starts_with(|c: char| c.is_ascii_digit())
+        || JS_RESERVED_WORDS.contains(&identifier.as_str())
+    {
+        identifier.insert(0, '_');
+    }
+    identifier
+}
+
+fn var_name(file: &CoordsFile) -> String {
+    // Routes may share the file name with a track, keep the variables apart
+    if file.trk_type == ROUTE_TYPE {
+        js_identifier(&(file.output_name.clone() + "_route"))
+    } else if file.multi_group {
+        // a map loading several groups would otherwise only keep the last copy
+        js_identifier(&format!("{}_{}", file.output_name, file.trk_type))
+    } else {
+        js_identifier(&file.output_name)
+    }
+}
+
+fn markers_var_name(file: &WaypointFile) -> String {
+    js_identifier(&(file.output_name.clone() + "_markers"))
+}
+
+// Two outputs with the same variable name would overwrite each other's file or JS variable
+fn check_output_names(parsed_files: &[CoordsFile], waypoint_files: &[WaypointFile]) -> Result<(), Vec<String>> {
+    let mut used: HashMap<String, &str> = HashMap::new();
+    let mut clashes: Vec<String> = Vec::new();
+    let names = parsed_files
+        .iter()
+        .map(|file| (var_name(file), file.name.as_str()))
+        .chain(waypoint_files.iter().map(|file| (markers_var_name(file), file.name.as_str())));
+    for (var_name, input_name) in names {
+        match used.get(&var_name) {
+            Some(other) => clashes.push(format!("{} is written by {} and {}", var_name, other, input_name)),
+            None => {
+                used.insert(var_name, input_name);
+            }
         }
     }
+    if clashes.is_empty() { Ok(()) } else { Err(clashes) }
+}
+
+fn format_point(point: &TrackPoint, options: &Options) -> String {
+    fn or_null<T: ToString>(value: Option<T>) -> String {
+        value.map_or("null".to_string(), |value| value.to_string())
+    }
+
+    let values: Vec<String> = options
+        .output_fields
+        .iter()
+        .map(|field| match field {
+            PointField::Lat => format_number(point.lat, options.precision),
+            PointField::Lng => format_number(point.lng, options.precision),
+            PointField::Ele => or_null(point.ele.map(|ele| format_number(ele, ELE_PRECISION))),
+            // Unix timestamp in seconds
+            PointField::Time => or_null(point.time.map(|time| time.timestamp())),
+            PointField::Hr => or_null(point.hr),
+            PointField::Cad => or_null(point.cad),
+            PointField::Atemp => or_null(point.atemp.map(|atemp| format_number(atemp, ATEMP_PRECISION))),
+        })
+        .collect();
+    values.join(",")
 }
 
 fn output_result_files(parsed_files: &Vec<CoordsFile>, options: &Options) {
@@ -490,9 +1560,7 @@ fn output_result_files(parsed_files: &Vec<CoordsFile>, options: &Options) {
     let out_path = Path::new(&options.output_path_str);
 
     for file in parsed_files {
-        let base_path = Path::new(&file.name);
-        let filename = base_path.file_name().unwrap();
-        let filename_str = filename.to_str().unwrap().replace(".gpx", ".js");
+        let filename_str = file.output_name.clone() + ".js";
 
         let file_out_path = out_path.join("coords_".to_owned() + &file.trk_type);
         fs::create_dir_all(file_out_path.to_str().unwrap()).unwrap();
@@ -503,18 +1571,78 @@ fn output_result_files(parsed_files: &Vec<CoordsFile>, options: &Options) {
         }
 
         let mut out_file = File::create(file_out_path).unwrap();
-        let var_name = filename.to_str().unwrap().replace(".gpx", "");
+        let var_name = var_name(file);
         out_file.write_all(b"var ").unwrap();
         out_file.write_all(var_name.as_bytes()).unwrap();
+        // Multi-polyline: one array of points per segment, see --fields
         out_file.write_all(b" = [").unwrap();
-        for coord in &file.coords {
-            let mut coord_str =
-                String::from("[") + &coord.lat.to_string() + "," + &coord.lng.to_string() + "]";
-            if coord != file.coords.last().unwrap() {
-                coord_str += ",";
-            }
-            out_file.write_all(coord_str.as_bytes()).unwrap();
+        let segments: Vec<String> = file
+            .segments
+            .iter()
+            .map(|segment| {
+                let coords: Vec<String> = segment
+                    .iter()
+                    .map(|coord| String::from("[") + &format_point(coord, options) + "]")
+                    .collect();
+                String::from("[") + &coords.join(",") + "]"
+            })
+            .collect();
+        out_file.write_all(segments.join(",").as_bytes()).unwrap();
+        out_file.write_all(b"];").unwrap();
+    }
+}
+
+fn js_string(value: &str) -> String {
+    let mut escaped = String::from("\"");
+    for c in value.chars() {
+        match c {
+            '"' => escaped += "\\\"",
+            '\\' => escaped += "\\\\",
+            '\n' => escaped += "\\n",
+            '\r' => escaped += "\\r",
+            '\t' => escaped += "\\t",
+            c if (c as u32) < 0x20 => escaped += &format!("\\u{:04x}", c as u32),
+            c => escaped.push(c),
         }
+    }
+    escaped.push('"');
+    escaped
+}
+
+fn output_waypoint_files(waypoint_files: &Vec<WaypointFile>, options: &Options) {
+    // Waypoints are written as marker lists, next to the coords_* directories
+    let markers_path = Path::new(&options.output_path_str).join("markers");
+
+    for file in waypoint_files {
+        fs::create_dir_all(&markers_path).unwrap();
+
+        let filename_str = file.output_name.clone() + ".js";
+        let file_out_path = markers_path.join(filename_str);
+
+        if options.verbose {
+            println!("Creating new file: {}", file_out_path.to_str().unwrap());
+        }
+
+        let mut out_file = File::create(file_out_path).unwrap();
+        let var_name = markers_var_name(file);
+        out_file.write_all(b"var ").unwrap();
+        out_file.write_all(var_name.as_bytes()).unwrap();
+        out_file.write_all(b" = [").unwrap();
+        let markers: Vec<String> = file
+            .waypoints
+            .iter()
+            .map(|waypoint| {
+                let mut marker = String::from("{lat:") + &format_number(waypoint.point.lat, options.precision)
+                    + ",lng:" + &format_number(waypoint.point.lng, options.precision);
+                for (key, value) in [("name", &waypoint.name), ("desc", &waypoint.desc), ("sym", &waypoint.sym)] {
+                    if let Some(value) = value {
+                        marker += &(String::from(",") + key + ":" + &js_string(value));
+                    }
+                }
+                marker + "}"
+            })
+            .collect();
+        out_file.write_all(markers.join(",").as_bytes()).unwrap();
         out_file.write_all(b"];").unwrap();
     }
 }
@@ -522,7 +1650,7 @@ fn output_result_files(parsed_files: &Vec<CoordsFile>, options: &Options) {
 fn count_points(parsed_files: &Vec<CoordsFile>) -> usize {
     let mut sum = 0;
     for file in parsed_files {
-        sum += file.coords.len();
+        sum += file.points().count();
     }
 
     sum
@@ -539,20 +1667,20 @@ fn point_in_rect(x:f64, y: f64, file: &CoordsFile) -> bool {
 fn boundary_overlap(file1: &CoordsFile, file2: &CoordsFile) -> bool {
 
     // top left
-    if point_in_rect(file1.min.lat, file1.min.lng, &file2) {
+    if point_in_rect(file1.min.lat, file1.min.lng, file2) {
         return true;
     }
     // top right
-    if point_in_rect(file1.max.lat, file1.min.lng, &file2) {
+    if point_in_rect(file1.max.lat, file1.min.lng, file2) {
         return true;
     }
 
     // bottom left
-    if point_in_rect(file1.min.lat, file1.max.lng, &file2) {
+    if point_in_rect(file1.min.lat, file1.max.lng, file2) {
         return true;
     }
     // bottom right
-    if point_in_rect(file1.max.lat, file1.max.lng, &file2) {
+    if point_in_rect(file1.max.lat, file1.max.lng, file2) {
         return true;
     }
 
@@ -560,9 +1688,9 @@ fn boundary_overlap(file1: &CoordsFile, file2: &CoordsFile) -> bool {
 }
 
 fn point_overlap(file1: &CoordsFile, file2: &CoordsFile) -> bool {
-    for point in &file1.coords {
-        for point2 in &file2.coords {
-            if point == point2 {
+    for point in file1.points() {
+        for point2 in file2.points() {
+            if point.same_position(point2) {
                 return true;
             }
         }
@@ -578,14 +1706,14 @@ fn insert_no_overlap(overlaps: &mut HashMap<String, OverLap>, not_overlap1: Stri
     }
 }*/
 
-fn insert_overlap(overlaps: &mut HashMap<String, OverLap>, overlap: &String, overlap_with: &String) {
+fn insert_overlap(overlaps: &mut HashMap<String, OverLap>, overlap: &str, overlap_with: &str) {
     if overlaps.contains_key(overlap) {
         let other_overlap = overlaps.get_mut(overlap).unwrap();
-        other_overlap.overlaps.insert(overlap_with.clone());
+        other_overlap.overlaps.insert(overlap_with.to_string());
     } else {
         let mut sub_new_overlap: OverLap = OverLap { overlaps: HashSet::new(), not_overlap: HashSet::new() };
-        sub_new_overlap.overlaps.insert(overlap_with.clone());
-        overlaps.insert(overlap.clone(), sub_new_overlap);
+        sub_new_overlap.overlaps.insert(overlap_with.to_string());
+        overlaps.insert(overlap.to_string(), sub_new_overlap);
     }
 }
 
@@ -623,13 +1751,13 @@ fn find_overlaps(parsed_files: &Vec<CoordsFile>, options: &Options) -> HashMap<S
                 }
             }
 
-            if boundary_overlap(&file, &sub_file) {
+            if boundary_overlap(file, sub_file) {
                 /*
                 if options.verbose {
                     println!("Found boundary overlap of {} and {}", file.name, sub_file.name)
                 }*/
 
-                if point_overlap(&file, &sub_file) {
+                if point_overlap(file, sub_file) {
 
                     if options.verbose {
                         println!("Found point overlap of {} and {}", file.name, sub_file.name)
@@ -652,6 +1780,13 @@ fn find_overlaps(parsed_files: &Vec<CoordsFile>, options: &Options) -> HashMap<S
     overlaps
 }
 
+fn print_failed_files(failed_files: &Vec<(String, ReadError)>) {
+    println!("Failed files: {}", failed_files.len());
+    for (name, error) in failed_files {
+        println!("  {}: {}", name, error);
+    }
+}
+
 fn print_min_max(parsed_files: &Vec<CoordsFile>) {
     for file in parsed_files {
         println!("File: {}, min: {},{}, max: {},{}", file.name, file.min.lat, file.min.lng, file.max.lat, file.max.lng);
@@ -667,21 +1802,30 @@ fn print_overlays(overlaps: &HashMap<String, OverLap>) {
 fn main() {
     let mut options = parse_args();
 
+    rayon::ThreadPoolBuilder::new()
+        .num_threads(options.jobs)
+        .build_global()
+        .unwrap();
+
     if !options.custom_group_str.is_empty() {
         println!("Reading Custom Groups...");
         read_custom_groups(&mut options);
     }
 
     if options.verbose {
-        println!("Input directory: {}", options.gpx_path_str);
+        println!("Input directories: {}", options.gpx_paths.join(", "));
         println!("Output directory: {}", options.output_path_str);
     }
     println!("Reading files...");
-    let mut parsed_files = read_files(&options);
+    let parsed_input = read_files(&options);
+    let mut parsed_files = parsed_input.parsed_files;
+    let waypoint_files = parsed_input.waypoint_files;
+    let failed_files = parsed_input.failed_files;
 
     // Test
     println!("Parsed files: {}", parsed_files.len());
     println!("Parsed points: {}", count_points(&parsed_files));
+    println!("Parsed waypoints: {}", waypoint_files.iter().map(|file| file.waypoints.len()).sum::<usize>());
 
     println!("Rounding values...");
     round_values(&mut parsed_files, &options);
@@ -689,27 +1833,137 @@ fn main() {
     println!("Removing duplicates in file...");
     remove_duplicates(&mut parsed_files, &options);
 
+    println!("Sorting activities by start time...");
+    sort_by_start_time(&mut parsed_files, &options);
+
     println!("Removing tracks without new points...");
     remove_files_without_new_points(&mut parsed_files, &options);
 
-    println!("Removing points on a straight line...");
-    remove_straight_line_points(&mut parsed_files, &options);
+    println!("Simplifying tracks...");
+    simplify_tracks(&mut parsed_files, &options);
 
     println!("Final files: {}", parsed_files.len());
     println!("Final points: {}", count_points(&parsed_files));
 
+    if let Err(clashes) = check_output_names(&parsed_files, &waypoint_files) {
+        for clash in clashes {
+            eprintln!("Output name clash: {}", clash);
+        }
+        eprintln!("Nothing written, rename the files or leave one out with --exclude or the skip list");
+        std::process::exit(1);
+    }
     output_result_files(&parsed_files, &options);
+    output_waypoint_files(&waypoint_files, &options);
 
     // TODO incomplete... rewrite
     if options.html_output {
-        if options.verbose || true {
-            print_min_max(&parsed_files)
-        }
+        print_min_max(&parsed_files);
 
         let overlap_list = find_overlaps(&parsed_files, &options);
 
-        if options.verbose || true {
-            print_overlays(&overlap_list)
+        print_overlays(&overlap_list);
+    }
+
+    if !failed_files.is_empty() {
+        print_failed_files(&failed_files);
+        if options.strict {
+            std::process::exit(1);
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ring(corners: &[(f64, f64)]) -> Vec<LatLng> {
+        corners.iter().map(|(lat, lng)| LatLng { lat: *lat, lng: *lng }).collect()
+    }
+
+    fn at(lat: f64, lng: f64) -> LatLng {
+        LatLng { lat, lng }
+    }
+
+    // 50..52 x 6..8 with a hole 50.5..51.5 x 6.5..7.5
+    fn square_with_hole() -> Polygon {
+        Polygon::new(
+            ring(&[(50.0, 6.0), (50.0, 8.0), (52.0, 8.0), (52.0, 6.0), (50.0, 6.0)]),
+            vec![ring(&[(50.5, 6.5), (50.5, 7.5), (51.5, 7.5), (51.5, 6.5), (50.5, 6.5)])],
+        )
+    }
+
+    #[test]
+    fn point_in_ring_inside_and_outside() {
+        let square = ring(&[(50.0, 6.0), (50.0, 8.0), (52.0, 8.0), (52.0, 6.0)]);
+        assert!(point_in_ring(&at(51.0, 7.0), &square));
+        assert!(!point_in_ring(&at(53.0, 7.0), &square));
+        assert!(!point_in_ring(&at(51.0, 5.0), &square));
+        assert!(!point_in_ring(&at(51.0, 7.0), &[]));
+    }
+
+    #[test]
+    fn point_in_ring_concave() {
+        // U shape open to the north
+        let u = ring(&[(0.0, 0.0), (0.0, 3.0), (3.0, 3.0), (3.0, 2.0), (1.0, 2.0), (1.0, 1.0), (3.0, 1.0), (3.0, 0.0)]);
+        assert!(point_in_ring(&at(2.0, 0.5), &u));
+        assert!(point_in_ring(&at(2.0, 2.5), &u));
+        assert!(!point_in_ring(&at(2.0, 1.5), &u));
+    }
+
+    #[test]
+    fn point_in_polygon_with_hole() {
+        let polygon = square_with_hole();
+        assert!(point_in_polygon(&at(50.2, 6.2), &polygon));
+        assert!(point_in_polygon(&at(51.8, 7.0), &polygon));
+        assert!(!point_in_polygon(&at(51.0, 7.0), &polygon));
+        assert!(!point_in_polygon(&at(49.0, 7.0), &polygon));
+    }
+
+    #[test]
+    fn point_in_region_multi_polygon() {
+        let far_away = Polygon::new(ring(&[(10.0, 10.0), (10.0, 11.0), (11.0, 11.0), (11.0, 10.0)]), vec![]);
+        let region = Region::Polygons(vec![square_with_hole(), far_away]);
+        assert!(point_in_region(&at(10.5, 10.5), &region));
+        assert!(point_in_region(&at(50.2, 6.2), &region));
+        assert!(!point_in_region(&at(51.0, 7.0), &region));
+    }
+
+    #[test]
+    fn js_identifier_avoids_invalid_names() {
+        assert_eq!(js_identifier("Morning_Run"), "Morning_Run");
+        assert_eq!(js_identifier("1234567"), "_1234567");
+        assert_eq!(js_identifier("2023-05-01_123_Tour"), "_2023_05_01_123_Tour");
+        assert_eq!(js_identifier(""), "_");
+        for word in ["with", "yield", "let", "undefined", "NaN"] {
+            assert_eq!(js_identifier(word), format!("_{}", word));
+        }
+    }
+
+    #[test]
+    fn output_names_clash_unless_group_is_added() {
+        let mut commute = CoordsFile::new("in/x.gpx", "commute");
+        commute.output_name = "x".to_string();
+        let mut berlin = commute.clone();
+        berlin.trk_type = "berlin".to_string();
+        assert!(check_output_names(&[commute.clone(), berlin.clone()], &[]).is_err());
+
+        commute.multi_group = true;
+        berlin.multi_group = true;
+        assert_eq!(var_name(&commute), "x_commute");
+        assert!(check_output_names(&[commute, berlin], &[]).is_ok());
+    }
+
+    #[test]
+    fn format_number_without_exponent_and_trailing_zeros() {
+        assert_eq!(format_number(1e-7, 6), "0");
+        assert_eq!(format_number(-0.0000001, 6), "0");
+        assert_eq!(format_number(10.0, 6), "10");
+        assert_eq!(format_number(51.3297930, 6), "51.329793");
+        assert_eq!(format_number(1e21, 2), "1000000000000000000000");
+        assert_eq!(format_number(120.0, 0), "120");
+        assert_eq!(format_number(12.4, 0), "12");
+        assert_eq!(format_number(-0.4, 0), "0");
+        assert_eq!(format_number(f64::NAN, 6), "null");
+        assert_eq!(format_number(f64::INFINITY, 6), "null");
+    }
+}
@@ -0,0 +1,272 @@
+// Metre based check whether a track adds anything to the tracks seen before
+//
+// Both methods use the same grid: rows of cell_size metres from the equator, every row split into
+// columns which are cell_size metres wide at the row's centre latitude. The cells have about the
+// same size everywhere, unlike a grid in degrees which gets narrower towards the poles.
+// - grid: a point is new if no earlier point fell into its cell
+// - buffer: a point is new if no earlier point is within radius_m, the grid is the spatial index
+// A track is kept if it reaches all minimums of NoveltyConfig, only kept tracks are added to the seen set.
+// With clip, a kept track is cut down to its runs of new points plus clip_margin points on both sides.
+
+use std::collections::HashMap;
+use std::collections::HashSet;
+use std::f64::consts::PI;
+
+use crate::CoordsFile;
+use crate::EARTH_RADIUS_M;
+use crate::TrackPoint;
+use crate::config::NoveltyConfig;
+use crate::config::NoveltyMethod;
+use crate::haversine_m;
+
+type Cell = (i64, i64);
+
+fn row_of(lat: f64, cell_size: f64) -> i64 {
+    (lat.to_radians() * EARTH_RADIUS_M / cell_size).floor() as i64
+}
+
+fn column_of(row: i64, lng: f64, cell_size: f64) -> i64 {
+    let center_lat = (row as f64 + 0.5) * cell_size / EARTH_RADIUS_M;
+    // the rows at the poles would get infinitely narrow columns
+    let row_circumference = (2.0 * PI * EARTH_RADIUS_M * center_lat.cos()).max(cell_size);
+    (lng / 360.0 * row_circumference / cell_size).floor() as i64
+}
+
+fn cell_of(point: &TrackPoint, cell_size: f64) -> Cell {
+    let row = row_of(point.lat, cell_size);
+    (row, column_of(row, point.lng, cell_size))
+}
+
+pub struct Novelty {
+    // distinct cells with new points
+    pub new_cells: usize,
+    // an edge counts fully when both ends are new, half when one end is new
+    pub new_length_m: f64,
+    pub length_m: f64,
+}
+
+impl Novelty {
+    pub fn new_percent(&self) -> f64 {
+        if self.length_m > 0.0 {
+            self.new_length_m / self.length_m * 100.0
+        } else if self.new_cells > 0 {
+            100.0
+        } else {
+            0.0
+        }
+    }
+
+    pub fn is_enough(&self, novelty_config: &NoveltyConfig) -> bool {
+        self.new_cells >= novelty_config.min_new_cells
+            && self.new_length_m >= novelty_config.min_new_length_m
+            && self.new_percent() >= novelty_config.min_new_percent
+    }
+}
+
+pub enum SeenSet {
+    Grid { cell_m: f64, cells: HashSet<Cell> },
+    Buffer { radius_m: f64, points: HashMap<Cell, Vec<(f64, f64)>> },
+}
+
+impl SeenSet {
+    pub fn new(novelty_config: &NoveltyConfig) -> SeenSet {
+        match novelty_config.method {
+            NoveltyMethod::Grid => SeenSet::Grid { cell_m: novelty_config.cell_m, cells: HashSet::new() },
+            NoveltyMethod::Buffer => SeenSet::Buffer { radius_m: novelty_config.radius_m, points: HashMap::new() },
+        }
+    }
+
+    pub fn is_new(&self, point: &TrackPoint) -> bool {
+        match self {
+            SeenSet::Grid { cell_m, cells } => !cells.contains(&cell_of(point, *cell_m)),
+            SeenSet::Buffer { radius_m, points } => {
+                // with cells of radius_m, every point within radius_m is in a neighbouring cell
+                let row = row_of(point.lat, *radius_m);
+                for neighbour_row in row - 1..=row + 1 {
+                    let column = column_of(neighbour_row, point.lng, *radius_m);
+                    for neighbour_column in column - 1..=column + 1 {
+                        let Some(seen) = points.get(&(neighbour_row, neighbour_column)) else {
+                            continue;
+                        };
+                        if seen
+                            .iter()
+                            .any(|(lat, lng)| haversine_m(point.lat, point.lng, *lat, *lng) <= *radius_m)
+                        {
+                            return false;
+                        }
+                    }
+                }
+                true
+            }
+        }
+    }
+
+    fn cell(&self, point: &TrackPoint) -> Cell {
+        match self {
+            SeenSet::Grid { cell_m, .. } => cell_of(point, *cell_m),
+            SeenSet::Buffer { radius_m, .. } => cell_of(point, *radius_m),
+        }
+    }
+
+    fn new_flags(&self, segment: &[TrackPoint]) -> Vec<bool> {
+        segment.iter().map(|point| self.is_new(point)).collect()
+    }
+
+    pub fn novelty(&self, coord_file: &CoordsFile) -> Novelty {
+        let mut new_cells: HashSet<Cell> = HashSet::new();
+        let mut new_length_m = 0.0;
+        let mut length_m = 0.0;
+        for segment in &coord_file.segments {
+            let is_new = self.new_flags(segment);
+            for (point, _) in segment.iter().zip(&is_new).filter(|(_, is_new)| **is_new) {
+                new_cells.insert(self.cell(point));
+            }
+            for (index, pair) in segment.windows(2).enumerate() {
+                let edge_m = pair[0].distance_m(&pair[1]);
+                length_m += edge_m;
+                match (is_new[index], is_new[index + 1]) {
+                    (true, true) => new_length_m += edge_m,
+                    (true, false) | (false, true) => new_length_m += edge_m / 2.0,
+                    (false, false) => {}
+                }
+            }
+        }
+        Novelty { new_cells: new_cells.len(), new_length_m, length_m }
+    }
+
+    pub fn novel_segments(&self, coord_file: &CoordsFile, margin: usize) -> Vec<Vec<TrackPoint>> {
+        let mut novel_segments = Vec::new();
+        for segment in &coord_file.segments {
+            let is_new = self.new_flags(segment);
+            let keep: Vec<bool> = (0..segment.len())
+                .map(|index| is_new[index.saturating_sub(margin)..=(index + margin).min(segment.len() - 1)].contains(&true))
+                .collect();
+
+            // a single point is no line, unless the whole segment is only one point
+            let min_run = segment.len().min(2);
+            let mut run: Vec<TrackPoint> = Vec::new();
+            for (point, keep) in segment.iter().zip(keep) {
+                if keep {
+                    run.push(point.clone());
+                    continue;
+                }
+                if run.len() >= min_run {
+                    novel_segments.push(std::mem::take(&mut run));
+                }
+                run.clear();
+            }
+            if run.len() >= min_run {
+                novel_segments.push(run);
+            }
+        }
+        novel_segments
+    }
+
+    pub fn insert(&mut self, point: &TrackPoint) {
+        match self {
+            SeenSet::Grid { cell_m, cells } => {
+                cells.insert(cell_of(point, *cell_m));
+            }
+            SeenSet::Buffer { radius_m, points } => {
+                points.entry(cell_of(point, *radius_m)).or_default().push((point.lat, point.lng));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // point north and east of lat, lng in metres
+    fn offset(lat: f64, lng: f64, north_m: f64, east_m: f64) -> TrackPoint {
+        TrackPoint::new(
+            lat + (north_m / EARTH_RADIUS_M).to_degrees(),
+            lng + (east_m / (EARTH_RADIUS_M * lat.to_radians().cos())).to_degrees(),
+        )
+    }
+
+    fn seen_set(method: NoveltyMethod) -> SeenSet {
+        SeenSet::new(&NoveltyConfig { method, ..NoveltyConfig::default() })
+    }
+
+    fn coords_file(segments: Vec<Vec<TrackPoint>>) -> CoordsFile {
+        let mut coord_file = CoordsFile::new("test.gpx", "cycling");
+        for segment in segments {
+            coord_file.add_segment(segment);
+        }
+        coord_file
+    }
+
+    const LATITUDES: [f64; 5] = [0.0, 45.0, 51.3, 69.6, 80.0];
+
+    #[test]
+    fn grid_cells_are_about_cell_m_wide_everywhere() {
+        for lat in LATITUDES {
+            let mut seen = seen_set(NoveltyMethod::Grid);
+            let start = TrackPoint::new(lat, 7.0);
+            seen.insert(&start);
+            assert!(!seen.is_new(&start));
+            // further than a cell diagonal in every direction
+            for (north_m, east_m) in [(20.0, 0.0), (-20.0, 0.0), (0.0, 20.0), (0.0, -20.0)] {
+                assert!(seen.is_new(&offset(lat, 7.0, north_m, east_m)), "lat {} {} {}", lat, north_m, east_m);
+            }
+        }
+    }
+
+    #[test]
+    fn buffer_finds_points_in_neighbour_cells() {
+        for lat in LATITUDES {
+            // shifted starts, so the circle around the point crosses row and column borders
+            for shift_m in [0.0, 3.7, 7.5, 11.2, 14.9] {
+                let mut seen = seen_set(NoveltyMethod::Buffer);
+                let start = offset(lat, 7.0, shift_m, shift_m);
+                seen.insert(&start);
+                for angle in (0..16).map(|step| step as f64 * std::f64::consts::PI / 8.0) {
+                    let (north, east) = (angle.sin(), angle.cos());
+                    let near = offset(start.lat, start.lng, north * 14.0, east * 14.0);
+                    let far = offset(start.lat, start.lng, north * 16.0, east * 16.0);
+                    assert!(!seen.is_new(&near), "near lat {} shift {} angle {}", lat, shift_m, angle);
+                    assert!(seen.is_new(&far), "far lat {} shift {} angle {}", lat, shift_m, angle);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn novelty_counts_new_cells_and_length() {
+        let mut seen = seen_set(NoveltyMethod::Buffer);
+        let known: Vec<TrackPoint> = (0..10).map(|index| offset(51.0, 7.0, index as f64 * 10.0, 0.0)).collect();
+        known.iter().for_each(|point| seen.insert(point));
+
+        // 100 m known, then 100 m new
+        let track: Vec<TrackPoint> = (0..20).map(|index| offset(51.0, 7.0, index as f64 * 10.0, 0.0)).collect();
+        let novelty = seen.novelty(&coords_file(vec![track]));
+        assert!(novelty.new_cells > 0);
+        assert!((novelty.length_m - 190.0).abs() < 0.5);
+        // the point at 100 m is within radius_m of the known one at 90 m, the edge after it counts half
+        assert!((novelty.new_length_m - 85.0).abs() < 0.5, "{}", novelty.new_length_m);
+        assert!((novelty.new_percent() - 85.0 / 190.0 * 100.0).abs() < 0.5);
+
+        let repeated = seen.novelty(&coords_file(vec![known]));
+        assert_eq!(repeated.new_cells, 0);
+        assert_eq!(repeated.new_length_m, 0.0);
+    }
+
+    #[test]
+    fn novel_segments_keep_margin() {
+        let mut seen = seen_set(NoveltyMethod::Buffer);
+        let track: Vec<TrackPoint> = (0..30).map(|index| offset(51.0, 7.0, index as f64 * 20.0, 0.0)).collect();
+        // only points 10..20 are new
+        track[..10].iter().chain(&track[20..]).for_each(|point| seen.insert(point));
+
+        let coord_file = coords_file(vec![track.clone()]);
+        let segments = seen.novel_segments(&coord_file, 1);
+        assert_eq!(segments.len(), 1);
+        assert_eq!(segments[0].len(), 12);
+        assert!(segments[0][0].same_position(&track[9]));
+        assert!(segments[0][11].same_position(&track[20]));
+
+        assert_eq!(seen.novel_segments(&coord_file, 0)[0].len(), 10);
+    }
+}
@@ -0,0 +1,258 @@
+// Track simplification with a tolerance in metres
+//
+// Every segment is projected to a local plane in metres (equirectangular around the segment's mean latitude),
+// which is accurate enough over the length of an activity.
+// - rdp: Ramer-Douglas-Peucker, no removed point is further than tolerance_m from the simplified line
+// - visvalingam: Visvalingam-Whyatt, points are removed while the triangle with their neighbours is smaller than
+//   tolerance_m * tolerance_m
+// The first and last point of a segment are always kept.
+
+use std::cmp::Ordering;
+use std::collections::BinaryHeap;
+
+use crate::EARTH_RADIUS_M;
+use crate::TrackPoint;
+use crate::config::SimplifyMethod;
+
+type Point = (f64, f64);
+
+fn project(segment: &[TrackPoint]) -> Vec<Point> {
+    let mean_lat = segment.iter().map(|point| point.lat).sum::<f64>() / segment.len() as f64;
+    let scale_x = EARTH_RADIUS_M * mean_lat.to_radians().cos();
+    segment
+        .iter()
+        .map(|point| (point.lng.to_radians() * scale_x, point.lat.to_radians() * EARTH_RADIUS_M))
+        .collect()
+}
+
+// Distance of p to the line piece from a to b
+fn distance_to_line(p: Point, a: Point, b: Point) -> f64 {
+    let (dx, dy) = (b.0 - a.0, b.1 - a.1);
+    let length_squared = dx * dx + dy * dy;
+    if length_squared == 0.0 {
+        return (p.0 - a.0).hypot(p.1 - a.1);
+    }
+    let t = (((p.0 - a.0) * dx + (p.1 - a.1) * dy) / length_squared).clamp(0.0, 1.0);
+    (p.0 - (a.0 + t * dx)).hypot(p.1 - (a.1 + t * dy))
+}
+
+fn triangle_area(a: Point, b: Point, c: Point) -> f64 {
+    ((b.0 - a.0) * (c.1 - a.1) - (c.0 - a.0) * (b.1 - a.1)).abs() / 2.0
+}
+
+fn rdp(points: &[Point], tolerance_m: f64) -> Vec<bool> {
+    let mut keep = vec![false; points.len()];
+    keep[0] = true;
+    keep[points.len() - 1] = true;
+
+    // explicit stack instead of recursion, tracks can have many thousand points
+    let mut ranges = vec![(0, points.len() - 1)];
+    while let Some((start, end)) = ranges.pop() {
+        let farthest = (start + 1..end)
+            .map(|index| (index, distance_to_line(points[index], points[start], points[end])))
+            .max_by(|a, b| a.1.total_cmp(&b.1));
+        if let Some((index, distance)) = farthest
+            && distance > tolerance_m
+        {
+            keep[index] = true;
+            ranges.push((start, index));
+            ranges.push((index, end));
+        }
+    }
+    keep
+}
+
+// Min-heap entry of the Visvalingam-Whyatt queue
+struct Candidate {
+    area: f64,
+    index: usize,
+}
+
+impl PartialEq for Candidate {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Candidate {}
+
+impl PartialOrd for Candidate {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Candidate {
+    fn cmp(&self, other: &Self) -> Ordering {
+        // reversed, BinaryHeap is a max-heap
+        other.area.total_cmp(&self.area).then_with(|| other.index.cmp(&self.index))
+    }
+}
+
+fn visvalingam(points: &[Point], tolerance_m: f64) -> Vec<bool> {
+    let max_area = tolerance_m * tolerance_m;
+    let count = points.len();
+    let mut keep = vec![true; count];
+    // linked list of the remaining points
+    let mut previous: Vec<usize> = (0..count).map(|index| index.saturating_sub(1)).collect();
+    let mut next: Vec<usize> = (0..count).map(|index| (index + 1).min(count - 1)).collect();
+    let mut areas = vec![f64::INFINITY; count];
+
+    let mut queue = BinaryHeap::new();
+    for index in 1..count - 1 {
+        areas[index] = triangle_area(points[index - 1], points[index], points[index + 1]);
+        queue.push(Candidate { area: areas[index], index });
+    }
+
+    let mut last_area: f64 = 0.0;
+    while let Some(Candidate { area, index }) = queue.pop() {
+        // outdated entry, the area changed when a neighbour was removed
+        if !keep[index] || area != areas[index] {
+            continue;
+        }
+        if area >= max_area {
+            break;
+        }
+        keep[index] = false;
+        // a point never gets a smaller area than the point removed before it
+        last_area = last_area.max(area);
+
+        let (before, after) = (previous[index], next[index]);
+        next[before] = after;
+        previous[after] = before;
+        for neighbour in [before, after] {
+            if neighbour == 0 || neighbour == count - 1 {
+                continue;
+            }
+            areas[neighbour] = triangle_area(points[previous[neighbour]], points[neighbour], points[next[neighbour]]).max(last_area);
+            queue.push(Candidate { area: areas[neighbour], index: neighbour });
+        }
+    }
+    keep
+}
+
+// Simplifies the segment in place, returns the number of removed points and the largest distance
+// of a removed point to the simplified line
+pub fn simplify_segment(segment: &mut Vec<TrackPoint>, method: SimplifyMethod, tolerance_m: f64) -> (usize, f64) {
+    if segment.len() < 3 {
+        return (0, 0.0);
+    }
+
+    let points = project(segment);
+    let keep = match method {
+        SimplifyMethod::Off => return (0, 0.0),
+        SimplifyMethod::Rdp => rdp(&points, tolerance_m),
+        SimplifyMethod::Visvalingam => visvalingam(&points, tolerance_m),
+    };
+
+    let mut max_deviation_m: f64 = 0.0;
+    let mut last_kept = 0;
+    for index in 1..points.len() {
+        if !keep[index] {
+            continue;
+        }
+        for removed in last_kept + 1..index {
+            max_deviation_m = max_deviation_m.max(distance_to_line(points[removed], points[last_kept], points[index]));
+        }
+        last_kept = index;
+    }
+
+    let before = segment.len();
+    let mut index = 0;
+    segment.retain(|_| {
+        index += 1;
+        keep[index - 1]
+    });
+    (before - segment.len(), max_deviation_m)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const METRES_PER_DEGREE: f64 = EARTH_RADIUS_M * std::f64::consts::PI / 180.0;
+
+    // point north and east of 51, 7 in metres
+    fn point(north_m: f64, east_m: f64) -> TrackPoint {
+        TrackPoint::new(
+            51.0 + north_m / METRES_PER_DEGREE,
+            7.0 + east_m / (METRES_PER_DEGREE * 51.0f64.to_radians().cos()),
+        )
+    }
+
+    fn straight_line(count: usize) -> Vec<TrackPoint> {
+        (0..count).map(|index| point(index as f64 * 10.0, index as f64 * 5.0)).collect()
+    }
+
+    const METHODS: [SimplifyMethod; 2] = [SimplifyMethod::Rdp, SimplifyMethod::Visvalingam];
+
+    #[test]
+    fn removes_collinear_points() {
+        for method in METHODS {
+            let mut segment = straight_line(10);
+            let (removed, deviation_m) = simplify_segment(&mut segment, method, 1.0);
+            assert_eq!(removed, 8);
+            assert_eq!(segment.len(), 2);
+            assert!(deviation_m < 0.01);
+        }
+    }
+
+    #[test]
+    fn keeps_spike_above_tolerance() {
+        for method in METHODS {
+            // a straight line with a 10 m spike in the middle
+            let spike = point(100.0, 10.0);
+            let mut segment: Vec<TrackPoint> = (0..=20)
+                .map(|index| if index == 10 { spike.clone() } else { point(index as f64 * 10.0, 0.0) })
+                .collect();
+            let (removed, _) = simplify_segment(&mut segment, method, 2.0);
+            assert!(removed > 0);
+            assert!(segment.iter().any(|point| point.same_position(&spike)));
+        }
+    }
+
+    #[test]
+    fn keeps_first_and_last_point() {
+        for method in METHODS {
+            let original = straight_line(20);
+            let mut segment = original.clone();
+            simplify_segment(&mut segment, method, 1000.0);
+            assert_eq!(segment.len(), 2);
+            assert!(segment[0].same_position(&original[0]));
+            assert!(segment[1].same_position(&original[19]));
+        }
+    }
+
+    #[test]
+    fn short_segments_are_unchanged() {
+        for method in METHODS {
+            for count in 0..3 {
+                let mut segment = straight_line(count);
+                assert_eq!(simplify_segment(&mut segment, method, 1000.0), (0, 0.0));
+                assert_eq!(segment.len(), count);
+            }
+        }
+    }
+
+    #[test]
+    fn off_is_unchanged() {
+        let mut segment = straight_line(10);
+        assert_eq!(simplify_segment(&mut segment, SimplifyMethod::Off, 1.0), (0, 0.0));
+        assert_eq!(segment.len(), 10);
+    }
+
+    #[test]
+    fn rdp_deviation_is_within_tolerance() {
+        // a wavy line with +-3 m sideways noise
+        let original: Vec<TrackPoint> = (0..500)
+            .map(|index| point(index as f64 * 5.0, (index as f64 * 0.7).sin() * 3.0 + (index as f64 * 0.05).sin() * 40.0))
+            .collect();
+        for tolerance_m in [0.5, 1.0, 2.0, 5.0] {
+            let mut segment = original.clone();
+            let (removed, deviation_m) = simplify_segment(&mut segment, SimplifyMethod::Rdp, tolerance_m);
+            assert!(removed > 0);
+            assert!(deviation_m > 0.0);
+            assert!(deviation_m <= tolerance_m, "{} > {}", deviation_m, tolerance_m);
+        }
+    }
+}
@@ -0,0 +1,102 @@
+// Garmin Training Center XML (*.tcx) reader
+//
+// Activities/Activity[@Sport]/Lap/Track/Trackpoint, every Track becomes one segment
+
+use crate::CoordsFile;
+use crate::Options;
+use crate::ReadError;
+use crate::TrackPoint;
+use crate::child_text;
+use crate::parse_time;
+
+fn sport_to_type(sport: &str) -> String {
+    // TCX only knows Running, Biking and Other
+    match sport {
+        "Biking" => "cycling".to_string(),
+        _ => sport.to_lowercase(),
+    }
+}
+
+fn read_degrees(position: roxmltree::Node, tag: &str) -> Result<Option<f64>, ReadError> {
+    match child_text(position, tag) {
+        Some(text) => text
+            .trim()
+            .parse::<f64>()
+            .map(Some)
+            .map_err(|_| ReadError::BadCoordinate(format!("{}=\"{}\"", tag, text))),
+        None => Ok(None),
+    }
+}
+
+fn read_trackpoint(trackpoint: roxmltree::Node) -> Result<Option<TrackPoint>, ReadError> {
+    // Trackpoints without a position (e.g. indoor or paused) are skipped
+    let position = match trackpoint.children().find(|n| n.has_tag_name("Position")) {
+        Some(position) => position,
+        None => return Ok(None),
+    };
+    let (lat, lng) = match (
+        read_degrees(position, "LatitudeDegrees")?,
+        read_degrees(position, "LongitudeDegrees")?,
+    ) {
+        (Some(lat), Some(lng)) => (lat, lng),
+        _ => return Ok(None),
+    };
+
+    let mut point = TrackPoint::new(lat, lng);
+    point.ele = child_text(trackpoint, "AltitudeMeters").and_then(|ele| ele.trim().parse::<f64>().ok());
+    point.time = child_text(trackpoint, "Time").and_then(|time| parse_time(&time));
+    point.hr = trackpoint
+        .children()
+        .find(|n| n.has_tag_name("HeartRateBpm"))
+        .and_then(|hr| child_text(hr, "Value"))
+        .and_then(|hr| hr.trim().parse::<u32>().ok());
+    point.cad = child_text(trackpoint, "Cadence").and_then(|cad| cad.trim().parse::<u32>().ok());
+
+    Ok(Some(point))
+}
+
+pub fn parse_tcx(fullpath: &str, text: &str, options: &Options) -> Result<Vec<CoordsFile>, ReadError> {
+    let opt = roxmltree::ParsingOptions {
+        allow_dtd: true,
+        ..roxmltree::ParsingOptions::default()
+    };
+    let doc = roxmltree::Document::parse_with_options(text, opt)?;
+
+    let mut activities = doc.descendants().filter(|n| n.has_tag_name("Activity")).peekable();
+    let sport = match activities.peek() {
+        Some(activity) => activity.attribute("Sport").unwrap_or(""),
+        None => return Err(ReadError::MissingTrack),
+    };
+
+    let mut coord_file = CoordsFile::new(fullpath, &sport_to_type(sport.trim()));
+    // the activity Id is its start time
+    coord_file.metadata_time = activities
+        .peek()
+        .and_then(|activity| child_text(*activity, "Id"))
+        .and_then(|id| parse_time(&id));
+    if options.verbose {
+        println!("Found activity sport {}", coord_file.trk_type);
+    }
+
+    for activity in activities {
+        for track in activity.descendants().filter(|n| n.has_tag_name("Track")) {
+            let mut segment: Vec<TrackPoint> = Vec::new();
+            for trackpoint in track.children().filter(|n| n.has_tag_name("Trackpoint")) {
+                if let Some(point) = read_trackpoint(trackpoint)? {
+                    if options.verbose {
+                        println!("Found point {} {}", point.lat, point.lng);
+                    }
+                    segment.push(point);
+                }
+            }
+            coord_file.add_segment(segment);
+        }
+    }
+
+    // e.g. indoor or treadmill activities
+    if coord_file.segments.is_empty() {
+        return Err(ReadError::MissingTrack);
+    }
+
+    Ok(vec![coord_file])
+}